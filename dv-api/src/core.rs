@@ -14,8 +14,17 @@ pub struct Output {
 pub trait UserImpl {
     //TODO:better path handling
     async fn file_attributes(&self, path: &U8Path) -> Result<(U8PathBuf, Option<FileAttributes>)>;
+    /// List everything below the directory `path`, including subdirectories but not `path` itself.
     async fn glob_file_meta(&self, path: &U8Path) -> Result<Vec<Metadata>>;
     async fn rm(&self, path: &U8Path) -> Result<()>;
+    /// Create `path` with its missing parents and apply the permissions in `attr` to it.
+    async fn create_dir(&self, path: &U8Path, attr: FileAttributes) -> Result<()>;
+    /// Remove the directory `path` if it's empty, returns `false` if it isn't.
+    async fn rmdir(&self, path: &U8Path) -> Result<bool>;
+    /// Rename `from` to `to`, replacing `to` if it exists.
+    async fn rename(&self, from: &U8Path, to: &U8Path) -> Result<()>;
+    /// Report changes below `path` until the receiver is dropped, `None` if it can't be watched.
+    async fn watch(&self, path: &U8Path) -> Result<Option<WatchEvents>>;
     async fn open(
         &self,
         path: &U8Path,
@@ -23,6 +32,8 @@ pub trait UserImpl {
         attr: FileAttributes,
     ) -> Result<BoxedFile>;
     async fn exec(&self, command: Script<'_, '_>) -> Result<Output>;
+    /// Run `command` without a terminal, its stdin and stdout are piped and stderr is discarded.
+    async fn spawn(&self, command: Script<'_, '_>) -> Result<BoxedPty>;
     async fn pty(&self, command: Script<'_, '_>, win_size: WindowSize) -> Result<BoxedPty>;
 }
 
@@ -33,6 +33,9 @@ bitflags::bitflags! {
     }
 }
 
-pub trait FileImpl: tokio::io::AsyncRead + tokio::io::AsyncWrite {}
+/// Paths changed below a watched path, closed when the watcher stops.
+pub type WatchEvents = tokio::sync::mpsc::UnboundedReceiver<U8PathBuf>;
+
+pub trait FileImpl: tokio::io::AsyncRead + tokio::io::AsyncWrite + tokio::io::AsyncSeek {}
 
 pub type BoxedFile = Box<dyn FileImpl + Unpin + Send>;
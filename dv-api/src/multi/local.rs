@@ -4,6 +4,9 @@ use std::path::{Path, PathBuf};
 mod config;
 pub use config::create;
 mod file;
+mod pipe;
+#[cfg(target_os = "linux")]
+mod watch;
 
 pub(crate) struct This {
     home: Option<PathBuf>,
@@ -52,6 +55,7 @@ impl UserImpl for This {
         if metadata.is_dir() {
             let mut result = Vec::new();
             for entry in walkdir::WalkDir::new(path2)
+                .min_depth(1)
                 .into_iter()
                 .filter_map(|e| e.ok())
             {
@@ -60,9 +64,6 @@ impl UserImpl for This {
                     Ok(meta) => meta,
                     Err(_) => continue,
                 };
-                if metadata.is_dir() {
-                    continue;
-                }
                 result.push(Metadata {
                     path: file_path.to_string_lossy().to_string().into(),
                     attr: (&metadata).into(),
@@ -87,6 +88,10 @@ impl UserImpl for This {
             stderr: output.stderr,
         })
     }
+    async fn spawn(&self, command: Script<'_, '_>) -> Result<BoxedPty> {
+        trace!("try to spawn command");
+        pipe::spawn(command)
+    }
     async fn pty(&self, command: Script<'_, '_>, win_size: WindowSize) -> Result<BoxedPty> {
         trace!("try to exec command");
         let pty = openpty_local(win_size, command)?;
@@ -105,6 +110,52 @@ impl UserImpl for This {
             Err(e) => Err(e.into()),
         }
     }
+    async fn create_dir(&self, path: &U8Path, attr: FileAttributes) -> Result<()> {
+        let path = self.canonicalize(path.as_str())?;
+        debug!("create_dir:{}", path.display());
+        tokio::fs::create_dir_all(&path).await?;
+        #[cfg(unix)]
+        if let Some(mode) = attr.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            let perm = std::fs::Permissions::from_mode(mode & 0o7777);
+            tokio::fs::set_permissions(&path, perm).await?;
+        }
+        #[cfg(windows)]
+        let _ = attr;
+        Ok(())
+    }
+    async fn rmdir(&self, path: &U8Path) -> Result<bool> {
+        let path = self.canonicalize(path.as_str())?;
+        debug!("rmdir:{}", path.display());
+        match tokio::fs::remove_dir(&path).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => Ok(false),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("{} not found", path.display());
+                Ok(true)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+    async fn rename(&self, from: &U8Path, to: &U8Path) -> Result<()> {
+        let from = self.canonicalize(from.as_str())?;
+        let to = self.canonicalize(to.as_str())?;
+        debug!("rename:{} -> {}", from.display(), to.display());
+        tokio::fs::rename(&from, &to).await?;
+        Ok(())
+    }
+    async fn watch(&self, path: &U8Path) -> Result<Option<WatchEvents>> {
+        let path = self.canonicalize(path.as_str())?;
+        #[cfg(target_os = "linux")]
+        {
+            Ok(Some(watch::watch(&path)?))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            debug!("no file watcher for {}", path.display());
+            Ok(None)
+        }
+    }
     async fn open(
         &self,
         path: &U8Path,
@@ -0,0 +1,77 @@
+//! Child processes driven through plain pipes instead of a terminal.
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout},
+};
+
+use super::{dev::*, exit_status2exit_code};
+
+struct Ctl(Child);
+
+#[async_trait]
+impl PtyCtl for Ctl {
+    async fn wait(&mut self) -> e4pty::Result<i32> {
+        Ok(exit_status2exit_code(self.0.wait().await?))
+    }
+}
+
+/// Stdin of the child, it's closed once the writer is dropped.
+struct Stdin(ChildStdin);
+
+impl AsyncWrite for Stdin {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl PtyWriter for Stdin {
+    async fn window_change(&self, _width: u16, _height: u16) -> e4pty::Result<()> {
+        Ok(())
+    }
+    async fn eof(&self) -> e4pty::Result<()> {
+        Ok(())
+    }
+}
+
+struct Stdout(ChildStdout);
+
+impl AsyncRead for Stdout {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl PtyReader for Stdout {}
+
+pub fn spawn(script: Script<'_, '_>) -> Result<BoxedPty> {
+    let mut builder = tokio::process::Command::from(script.into_command()?);
+    builder
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true);
+    let mut child = builder.spawn()?;
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok(BoxedPty::new(Ctl(child), Stdin(stdin), Stdout(stdout)))
+}
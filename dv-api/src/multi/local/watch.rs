@@ -0,0 +1,111 @@
+//! Recursive directory watching with inotify.
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    mem::MaybeUninit,
+    os::{fd::OwnedFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+};
+
+use rustix::{
+    fs::inotify::{self, CreateFlags, ReadFlags, WatchFlags},
+    io::Errno,
+};
+use tokio::{io::unix::AsyncFd, sync::mpsc::UnboundedSender};
+use tracing::warn;
+
+use super::dev::*;
+
+const FLAGS: WatchFlags = WatchFlags::CREATE
+    .union(WatchFlags::DELETE)
+    .union(WatchFlags::MODIFY)
+    .union(WatchFlags::CLOSE_WRITE)
+    .union(WatchFlags::ATTRIB)
+    .union(WatchFlags::MOVE);
+
+/// Watch `root` and, if it's a directory, every directory below it.
+pub fn watch(root: &Path) -> Result<WatchEvents> {
+    let fd = inotify::init(CreateFlags::CLOEXEC | CreateFlags::NONBLOCK)
+        .map_err(std::io::Error::from)?;
+    let mut dirs = HashMap::new();
+    add_tree(&fd, &mut dirs, root);
+    if dirs.is_empty() {
+        whatever!("failed to watch {}", root.display())
+    }
+    let fd = AsyncFd::new(fd)?;
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(run(fd, dirs, tx));
+    Ok(rx)
+}
+
+fn add_tree(fd: &OwnedFd, dirs: &mut HashMap<i32, PathBuf>, root: &Path) {
+    let tree = walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_map(|e| e.ok());
+    for entry in tree.filter(|e| e.depth() == 0 || e.file_type().is_dir()) {
+        match inotify::add_watch(fd, entry.path(), FLAGS) {
+            Ok(wd) => {
+                dirs.insert(wd, entry.path().to_path_buf());
+            }
+            Err(e) => warn!("failed to watch {}: {e}", entry.path().display()),
+        }
+    }
+}
+
+async fn run(
+    fd: AsyncFd<OwnedFd>,
+    mut dirs: HashMap<i32, PathBuf>,
+    tx: UnboundedSender<U8PathBuf>,
+) {
+    let mut buf = vec![MaybeUninit::uninit(); 16 * 1024];
+    loop {
+        let mut guard = tokio::select! {
+            guard = fd.readable() => match guard {
+                Ok(guard) => guard,
+                Err(e) => {
+                    warn!("inotify error: {e}");
+                    return;
+                }
+            },
+            _ = tx.closed() => return,
+        };
+        let mut created = Vec::new();
+        let mut reader = inotify::Reader::new(fd.get_ref(), &mut buf);
+        loop {
+            let event = match reader.next() {
+                Ok(event) => event,
+                Err(Errno::AGAIN) => {
+                    guard.clear_ready();
+                    break;
+                }
+                Err(e) => {
+                    warn!("inotify error: {e}");
+                    return;
+                }
+            };
+            let flags = event.events();
+            if flags.contains(ReadFlags::IGNORED) {
+                dirs.remove(&event.wd());
+                continue;
+            }
+            let Some(dir) = dirs.get(&event.wd()) else {
+                continue;
+            };
+            let path = match event.file_name() {
+                Some(name) => dir.join(OsStr::from_bytes(name.to_bytes())),
+                None => dir.clone(),
+            };
+            if flags.contains(ReadFlags::ISDIR)
+                && flags.intersects(ReadFlags::CREATE | ReadFlags::MOVED_TO)
+            {
+                created.push(path.clone());
+            }
+            if tx.send(path.to_string_lossy().to_string().into()).is_err() {
+                return;
+            }
+        }
+        for dir in created {
+            add_tree(fd.get_ref(), &mut dirs, &dir);
+        }
+    }
+}
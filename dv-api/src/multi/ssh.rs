@@ -1,6 +1,11 @@
+use std::sync::Arc;
+
 use super::dev::{self, *};
 use russh::client;
-use russh_sftp::{client::SftpSession, protocol::StatusCode};
+use russh_sftp::{
+    client::{RawSftpSession, SftpSession},
+    protocol::StatusCode,
+};
 use tracing::{info, warn};
 
 mod config;
@@ -23,6 +28,8 @@ impl client::Handler for Client {
 pub(crate) struct SSHSession {
     session: Vec<client::Handle<Client>>,
     sftp: SftpSession,
+    /// Serves the files opened for writing only, see [`file::Writer`].
+    writes: Arc<RawSftpSession>,
     home: Option<String>,
 }
 
@@ -136,7 +143,11 @@ impl UserImpl for SSHSession {
                 for entry in self.sftp.read_dir(&path).await? {
                     let sub_path = format!("{}/{}", path, entry.file_name());
                     if entry.file_type().is_dir() {
-                        stack.push(sub_path);
+                        stack.push(sub_path.clone());
+                        infos.push(Metadata {
+                            path: sub_path.into(),
+                            attr: entry.metadata(),
+                        });
                         continue;
                     }
                     if !entry.file_type().is_file() {
@@ -171,6 +182,13 @@ impl UserImpl for SSHSession {
             stderr: Vec::new(),
         })
     }
+    async fn spawn(&self, command: Script<'_, '_>) -> Result<BoxedPty> {
+        let channel = self.session().channel_open_session().await?;
+        let cmd = self.prepare_command(command).await?;
+        info!("spawn {}", cmd);
+        channel.exec(true, cmd).await?;
+        Ok(channel.into_pty())
+    }
     async fn rm(&self, path: &U8Path) -> Result<()> {
         let path = self.canonicalize(path)?;
         debug!("rm: {}", path);
@@ -185,6 +203,122 @@ impl UserImpl for SSHSession {
             Err(e) => Err(e.into()),
         }
     }
+    async fn create_dir(&self, path: &U8Path, attr: FileAttributes) -> Result<()> {
+        let path = self.canonicalize(path)?;
+        debug!("create_dir: {}", path);
+        match self.sftp.create_dir(path.as_ref()).await {
+            Ok(_) => {}
+            Err(russh_sftp::client::error::Error::Status(s))
+                if s.status_code == StatusCode::NoSuchFile =>
+            {
+                self.create_parent(path.as_ref()).await?;
+                self.sftp.create_dir(path.as_ref()).await?;
+            }
+            // already exists
+            Err(russh_sftp::client::error::Error::Status(s))
+                if s.status_code == StatusCode::Failure
+                    && self.sftp.metadata(path.as_ref()).await?.is_dir() => {}
+            Err(e) => Err(e)?,
+        }
+        if let Some(mode) = attr.permissions {
+            let mut attr = FileAttributes::empty();
+            attr.permissions = Some(mode & 0o7777);
+            self.sftp.set_metadata(path.as_ref(), attr).await?;
+        }
+        Ok(())
+    }
+    async fn rmdir(&self, path: &U8Path) -> Result<bool> {
+        let path = self.canonicalize(path)?;
+        debug!("rmdir: {}", path);
+        match self.sftp.remove_dir(path.as_ref()).await {
+            Ok(_) => Ok(true),
+            Err(russh_sftp::client::error::Error::Status(s))
+                if s.status_code == StatusCode::NoSuchFile =>
+            {
+                debug!("{} not found", path);
+                Ok(true)
+            }
+            // SFTPv3 has no dedicated status for a non-empty directory
+            Err(russh_sftp::client::error::Error::Status(s))
+                if s.status_code == StatusCode::Failure
+                    && self.sftp.read_dir(path.as_ref()).await?.next().is_some() =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+    async fn rename(&self, from: &U8Path, to: &U8Path) -> Result<()> {
+        let from = self.canonicalize(from)?;
+        let to = self.canonicalize(to)?;
+        debug!("rename: {} -> {}", from, to);
+        match self.sftp.rename(from.as_ref(), to.as_ref()).await {
+            Ok(_) => Ok(()),
+            // SFTPv3 rename doesn't replace an existing file, but the status is as generic for
+            // e.g. a denied permission or a full disk, so the target is kept until it is replaced
+            Err(russh_sftp::client::error::Error::Status(s))
+                if s.status_code == StatusCode::Failure
+                    && self.sftp.try_exists(to.as_ref()).await? =>
+            {
+                let backup = format!("{to}.dvbak");
+                self.sftp.rename(to.as_ref(), backup.as_str()).await?;
+                if let Err(e) = self.sftp.rename(from.as_ref(), to.as_ref()).await {
+                    self.sftp.rename(backup.as_str(), to.as_ref()).await?;
+                    return Err(e.into());
+                }
+                if let Err(e) = self.sftp.remove_file(backup.as_str()).await {
+                    warn!("failed to remove {backup}: {e}");
+                }
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+    async fn watch(&self, path: &U8Path) -> Result<Option<WatchEvents>> {
+        let path = self.canonicalize(path)?;
+        let path = format!("'{}'", path.replace('\'', r"'\''"));
+        let probe = self
+            .exec(Script::Whole("command -v inotifywait >/dev/null"))
+            .await?;
+        if probe.code != 0 {
+            debug!("no inotifywait on remote, can't watch {}", path);
+            return Ok(None);
+        }
+        let channel = self.session().channel_open_session().await?;
+        let cmd = format!(
+            "inotifywait -mrq -e close_write,create,delete,move,attrib --format '%w%f' {path}"
+        );
+        info!("exec {}", cmd);
+        channel.exec(true, cmd).await?;
+        let pty = channel.into_pty();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            use tokio::io::AsyncBufReadExt;
+            // keep the channel open while watching
+            let (ctl, writer, reader) = pty.destruct();
+            let mut lines = tokio::io::BufReader::new(reader).lines();
+            loop {
+                let line = tokio::select! {
+                    line = lines.next_line() => line,
+                    _ = tx.closed() => break,
+                };
+                match line {
+                    Ok(Some(line)) => {
+                        if tx.send(line.trim_end().into()).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("inotifywait error: {e}");
+                        break;
+                    }
+                }
+            }
+            drop((ctl, writer));
+        });
+        Ok(Some(rx))
+    }
     async fn pty(&self, command: Script<'_, '_>, win_size: WindowSize) -> Result<BoxedPty> {
         debug!("open pty with size: {:?}", win_size);
         let channel = self.session().channel_open_session().await?;
@@ -214,6 +348,26 @@ impl UserImpl for SSHSession {
         let path = path2.as_ref();
         trace!("open: {}, flags: {:?}", path, flags);
         let open_flags = flags.into();
+        if flags.contains(OpenFlags::WRITE)
+            && !flags.intersects(OpenFlags::READ | OpenFlags::APPEND)
+        {
+            let handle = loop {
+                match self.writes.open(path, open_flags, attr.clone()).await {
+                    Ok(handle) => break Ok(handle),
+                    Err(russh_sftp::client::error::Error::Status(s))
+                        if s.status_code == StatusCode::NoSuchFile
+                            && flags.contains(OpenFlags::CREATE) =>
+                    {
+                        self.create_parent(path).await?;
+                    }
+                    Err(e) => break Err(e),
+                }
+            }?;
+            return Ok(Box::new(file::Writer::new(
+                self.writes.clone(),
+                handle.handle,
+            )));
+        }
         let file = loop {
             match self
                 .sftp
@@ -8,13 +8,16 @@ use tracing::{info, warn};
 use super::{Client, SSHSession, dev::*};
 
 static SSH_CONFIG: std::sync::LazyLock<ssh2_config::SshConfig> = std::sync::LazyLock::new(|| {
-    ssh2_config::SshConfig::parse_default_file(ssh2_config::ParseRule::STRICT)
-        .expect("failed to parse ssh config")
+    ssh2_config::SshConfig::parse_default_file(ssh2_config::ParseRule::STRICT).unwrap_or_else(|e| {
+        warn!("no ssh config: {e}");
+        Default::default()
+    })
 });
 
 pub async fn create(host: String, cfg: &mut Config) -> Result<BoxedUser> {
     debug!("ssh create: {}", host);
-    let (clients, user) = connect(&host, cfg.get("passwd").cloned()).await?;
+    let (clients, host_cfg) = connect(&host, cfg).await?;
+    let user = host_cfg.user.clone().expect("no user");
     if cfg.get("user").is_none() {
         cfg.set("user", user.clone());
     }
@@ -25,6 +28,11 @@ pub async fn create(host: String, cfg: &mut Config) -> Result<BoxedUser> {
     let channel = client.channel_open_session().await?;
     channel.request_subsystem(true, "sftp").await?;
     let sftp = russh_sftp::client::SftpSession::new(channel.into_stream()).await?;
+    // a raw session of its own, so writes can be sent without waiting on each other
+    let channel = client.channel_open_session().await?;
+    channel.request_subsystem(true, "sftp").await?;
+    let writes = russh_sftp::client::RawSftpSession::new(channel.into_stream());
+    writes.init().await?;
 
     let home = match os {
         Os::Linux(_) | Os::MacOs | Os::Unix => env.get("HOME"),
@@ -34,9 +42,16 @@ pub async fn create(host: String, cfg: &mut Config) -> Result<BoxedUser> {
     .cloned();
     cfg.variables.extend(env);
     cfg.set("os", os.to_string());
+    // how other hosts reach this one, e.g. for direct transfers
+    if cfg.get("ssh_addr").is_none() {
+        let host_name = host_cfg.host_name.as_deref().unwrap_or(&host);
+        cfg.set("ssh_addr", format!("{user}@{host_name}"));
+        cfg.set("ssh_port", host_cfg.port.unwrap_or(22).to_string());
+    }
     let sys = SSHSession {
         session: clients,
         sftp,
+        writes: Arc::new(writes),
         home,
     };
     let u: BoxedUser = sys.into();
@@ -117,12 +132,29 @@ async fn connect_impl(
     whatever!("ssh connect {} {} failed", host_name, username)
 }
 
-async fn connect(host: &str, passwd: Option<String>) -> Result<(Vec<Handle<Client>>, String)> {
-    let host_cfg = SSH_CONFIG.query(host);
+/// Connect to `host` as configured in `~/.ssh/config`.
+///
+/// Like `ssh`, a host without a `HostName` is reached by its name. The `port` and `user` of
+/// `cfg` take precedence over the ssh config, `user` only if it was given before connecting.
+async fn connect(
+    host: &str,
+    cfg: &Config,
+) -> Result<(Vec<Handle<Client>>, ssh2_config::HostParams)> {
+    let mut host_cfg = SSH_CONFIG.query(host);
+    host_cfg.host_name.get_or_insert_with(|| host.to_string());
+    if let Some(port) = cfg.get("port") {
+        let Ok(port) = port.parse() else {
+            whatever!("invalid port {port} of {host}")
+        };
+        host_cfg.port = Some(port);
+    }
+    if let Some(user) = cfg.get("user") {
+        host_cfg.user = Some(user.clone());
+    }
     trace!("connect to {:?}", host_cfg);
     let mut clients = vec![];
-    connect_impl(&host_cfg, &mut clients, passwd).await?;
-    Ok((clients, host_cfg.user.expect("no user").to_string()))
+    connect_impl(&host_cfg, &mut clients, cfg.get("passwd").cloned()).await?;
+    Ok((clients, host_cfg))
 }
 
 async fn detect2(h: &Handle<Client>, os: &mut Os) -> Result<HashMap<String, String>> {
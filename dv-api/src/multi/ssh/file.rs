@@ -1,4 +1,16 @@
+use std::{
+    io::{self, SeekFrom},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+};
+
 use async_trait::async_trait;
+use russh_sftp::client::{RawSftpSession, rawsession::SftpResult};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf},
+    task::JoinSet,
+};
 
 use super::{FileImpl, OpenFlags};
 use russh_sftp::protocol::OpenFlags as ThisOpenFlags;
@@ -30,3 +42,156 @@ impl From<OpenFlags> for ThisOpenFlags {
 
 #[async_trait]
 impl FileImpl for russh_sftp::client::fs::File {}
+
+/// Bytes of one write request, the size every SFTP server accepts.
+const WRITE_LEN: usize = 32 * 1024;
+/// Write requests in flight at once.
+const MAX_WRITES: usize = 16;
+
+fn io_error(e: russh_sftp::client::error::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// A remote file opened for writing only, which sends its writes without waiting for the status
+/// of the previous one.
+///
+/// A write is accepted once its request is sent, so up to [`MAX_WRITES`] requests are in flight
+/// and a failure is returned by a later write, flush or shutdown.
+pub(super) struct Writer {
+    session: Arc<RawSftpSession>,
+    handle: String,
+    /// The offset of the next write.
+    pos: u64,
+    writes: JoinSet<SftpResult<()>>,
+    /// The size of the file for a seek from its end.
+    seek: Option<Pin<Box<dyn Future<Output = SftpResult<u64>> + Send>>>,
+    close: Option<Pin<Box<dyn Future<Output = SftpResult<()>> + Send>>>,
+    closed: bool,
+}
+
+impl Writer {
+    pub fn new(session: Arc<RawSftpSession>, handle: String) -> Self {
+        Self {
+            session,
+            handle,
+            pos: 0,
+            writes: JoinSet::new(),
+            seek: None,
+            close: None,
+            closed: false,
+        }
+    }
+    /// Collect the finished writes, pending while more than `max` are in flight.
+    fn poll_writes(&mut self, cx: &mut Context<'_>, max: usize) -> Poll<io::Result<()>> {
+        while self.writes.len() > max {
+            match ready!(self.writes.poll_join_next(cx)) {
+                Some(res) => res.map_err(io::Error::other)?.map_err(io_error)?,
+                None => break,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if let Ok(rt) = tokio::runtime::Handle::try_current() {
+            let (session, handle) = (self.session.clone(), self.handle.clone());
+            rt.spawn(async move {
+                let _ = session.close(handle).await;
+            });
+        }
+    }
+}
+
+impl AsyncWrite for Writer {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_writes(cx, MAX_WRITES - 1))?;
+        let len = buf.len().min(WRITE_LEN);
+        let (session, handle, offset) = (self.session.clone(), self.handle.clone(), self.pos);
+        let data = buf[..len].to_vec();
+        self.writes.spawn(async move {
+            session.write(handle, offset, data).await?;
+            Ok(())
+        });
+        self.pos += len as u64;
+        Poll::Ready(Ok(len))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_writes(cx, 0)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_writes(cx, 0))?;
+        if self.closed {
+            return Poll::Ready(Ok(()));
+        }
+        let close = match &mut self.close {
+            Some(close) => close,
+            None => {
+                let (session, handle) = (self.session.clone(), self.handle.clone());
+                self.close.insert(Box::pin(async move {
+                    session.close(handle).await?;
+                    Ok(())
+                }))
+            }
+        };
+        let res = ready!(close.as_mut().poll(cx));
+        self.close = None;
+        self.closed = true;
+        Poll::Ready(res.map_err(io_error))
+    }
+}
+
+impl AsyncSeek for Writer {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let offset = |base: u64, delta: i64| {
+            base.checked_add_signed(delta)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))
+        };
+        match position {
+            SeekFrom::Start(pos) => self.pos = pos,
+            SeekFrom::Current(delta) => self.pos = offset(self.pos, delta)?,
+            SeekFrom::End(delta) => {
+                let (session, handle) = (self.session.clone(), self.handle.clone());
+                self.seek = Some(Box::pin(async move {
+                    let size = session.fstat(handle).await?.attrs.size.unwrap_or(0);
+                    Ok(offset(size, delta)?)
+                }));
+            }
+        }
+        Ok(())
+    }
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        // the size only counts the writes that are done
+        ready!(self.poll_writes(cx, 0))?;
+        if let Some(seek) = &mut self.seek {
+            let res = ready!(seek.as_mut().poll(cx));
+            self.seek = None;
+            self.pos = res.map_err(io_error)?;
+        }
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+impl AsyncRead for Writer {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        _: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "file opened for writing only",
+        )))
+    }
+}
+
+#[async_trait]
+impl FileImpl for Writer {}
@@ -1,16 +1,20 @@
 use super::dev::*;
 use crate::{
-    DeviceInfo,
+    DeviceInfo, HttpClient,
     db::MultiDB,
     interactor::{DynInteractor, Interactor},
 };
 use std::{collections::HashMap, sync::Arc};
 
+use crate::Limiter;
+
 #[derive(Debug)]
 pub struct Device {
     pub info: DeviceInfo,
     pub system: Option<String>,
     pub users: Vec<String>,
+    /// The bandwidth limit shared by the users of this device.
+    pub limiter: Option<Arc<Limiter>>,
 }
 
 impl Device {
@@ -19,6 +23,7 @@ impl Device {
             info,
             system: None,
             users: Vec::new(),
+            limiter: None,
         }
     }
 }
@@ -29,6 +34,8 @@ pub struct Context {
     pub users: HashMap<String, User>,
     pub devices: HashMap<String, Device>,
     pub cache_dir: Option<std::path::PathBuf>,
+    /// The client of downloads, replace it to configure proxies, certificates or retries.
+    pub http: HttpClient,
 }
 
 impl Context {
@@ -43,6 +50,7 @@ impl Context {
             users: HashMap::new(),
             devices: HashMap::new(),
             cache_dir,
+            http: HttpClient::default(),
         }
     }
     pub fn contains_user<Q>(&self, uid: &Q) -> bool
@@ -82,6 +90,11 @@ impl Context {
                     user.vars.insert("os".to_string(), dev.info.os.to_string());
                 }
             }
+            match (&dev.limiter, &user.limiter) {
+                (Some(limiter), _) => user.limiter = Some(limiter.clone()),
+                (None, Some(limiter)) => dev.limiter = Some(limiter.clone()),
+                (None, None) => {}
+            }
             if user.is_system {
                 dev.system = Some(uid.clone());
             } else {
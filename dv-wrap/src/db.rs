@@ -1,19 +1,249 @@
 use super::dev::{self, *};
 
+mod file;
+mod memory;
 mod sqlite;
+pub use file::FileDb;
+pub use memory::MemoryDb;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 pub use sqlite::Sqlite;
 use tracing::warn;
 
+/// The operation that wrote a [`Record`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum OpKind {
+    Sync,
+    Download,
+    /// Progress of an interrupted copy or download.
+    Resume,
+    Once,
+    /// An archive extracted into the cache.
+    Extract,
+    /// A download installed onto a user.
+    Deploy,
+    /// The release tag resolved for a user.
+    Release,
+}
+
+/// A cache record of one path of a device.
+///
+/// `version` and `latest` are the opaque strings of [`DB::get`]; the other fields are optional
+/// typed state for operations that need more.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Record {
+    pub version: String,
+    pub latest: String,
+    /// Hex digest of the content, prefixed with the algorithm, e.g. `sha256:...`.
+    pub hash: Option<String>,
+    pub size: Option<u64>,
+    pub mode: Option<u32>,
+    /// Unix time in seconds the path was last modified.
+    pub mtime: Option<i64>,
+    /// Unix time in seconds the source the path was copied from was last modified.
+    pub src_mtime: Option<i64>,
+    /// Unix time in seconds the record was written.
+    pub synced_at: Option<i64>,
+    pub kind: Option<OpKind>,
+    /// Free-form JSON document, stored as is.
+    pub meta: Option<String>,
+}
+
+impl Record {
+    /// A record written by `kind` now.
+    pub fn new(kind: OpKind, version: impl Into<String>, latest: impl Into<String>) -> Self {
+        Self {
+            version: version.into(),
+            latest: latest.into(),
+            synced_at: Some(now()),
+            kind: Some(kind),
+            ..Default::default()
+        }
+    }
+}
+
+/// A record with the device and path it belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub uid: String,
+    pub path: String,
+    pub record: Record,
+}
+
+/// A [`CacheEntry`] as it is written to the JSON and TOML files, with `meta` as `M`.
+#[derive(Serialize, Deserialize)]
+struct StoredEntry<M = String> {
+    device: String,
+    key: String,
+    version: String,
+    latest: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    src_mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    synced_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta: Option<M>,
+}
+
+impl<M> StoredEntry<M> {
+    fn map_meta<N>(self, f: impl FnOnce(M) -> Result<N>) -> Result<StoredEntry<N>> {
+        Ok(StoredEntry {
+            device: self.device,
+            key: self.key,
+            version: self.version,
+            latest: self.latest,
+            hash: self.hash,
+            size: self.size,
+            mode: self.mode,
+            mtime: self.mtime,
+            src_mtime: self.src_mtime,
+            synced_at: self.synced_at,
+            kind: self.kind,
+            meta: self.meta.map(f).transpose()?,
+        })
+    }
+}
+
+impl From<CacheEntry> for StoredEntry {
+    fn from(e: CacheEntry) -> Self {
+        let r = e.record;
+        Self {
+            device: e.uid,
+            key: e.path,
+            version: r.version,
+            latest: r.latest,
+            hash: r.hash,
+            size: r.size,
+            mode: r.mode,
+            mtime: r.mtime,
+            src_mtime: r.src_mtime,
+            synced_at: r.synced_at,
+            kind: r.kind.map(|k| k.to_string()),
+            meta: r.meta,
+        }
+    }
+}
+
+impl TryFrom<StoredEntry> for CacheEntry {
+    type Error = anyhow::Error;
+    fn try_from(e: StoredEntry) -> Result<Self> {
+        let kind = match e.kind {
+            Some(k) => Some(k.parse().map_err(|_| anyhow::anyhow!("unknown kind {k}"))?),
+            None => None,
+        };
+        Ok(Self {
+            uid: e.device,
+            path: e.key,
+            record: Record {
+                version: e.version,
+                latest: e.latest,
+                hash: e.hash,
+                size: e.size,
+                mode: e.mode,
+                mtime: e.mtime,
+                src_mtime: e.src_mtime,
+                synced_at: e.synced_at,
+                kind,
+                meta: e.meta,
+            },
+        })
+    }
+}
+
+/// A JSON array with one entry per line.
+///
+/// A `meta` that is itself JSON is embedded as is, anything else is written as a string.
+fn to_json_array(entries: &[CacheEntry]) -> Result<String> {
+    let mut json = String::from("[");
+    for (i, entry) in entries.iter().enumerate() {
+        let entry = StoredEntry::from(entry.clone()).map_meta(|meta| {
+            Ok(RawValue::from_string(meta.clone())
+                .or_else(|_| serde_json::value::to_raw_value(&meta))?)
+        })?;
+        json.push_str(if i == 0 { "\n  " } else { ",\n  " });
+        json.push_str(&serde_json::to_string(&entry)?);
+    }
+    json.push_str("\n]\n");
+    Ok(json)
+}
+
+fn from_json_array(json: &str) -> Result<Vec<CacheEntry>> {
+    serde_json::from_str::<Vec<StoredEntry<Box<RawValue>>>>(json)?
+        .into_iter()
+        .map(|entry| {
+            let entry = entry.map_meta(|meta| {
+                Ok(serde_json::from_str::<String>(meta.get())
+                    .unwrap_or_else(|_| meta.get().to_string()))
+            })?;
+            CacheEntry::try_from(entry)
+        })
+        .collect()
+}
+
+/// The current unix time in seconds.
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
+}
+
 #[async_trait::async_trait]
 pub trait DB {
-    async fn get(&self, uid: &str, path: &str) -> Result<Option<(String, String)>>;
-    async fn set(&self, uid: &str, path: &str, version: &str, latest: &str) -> Result<()>;
+    async fn get_record(&self, uid: &str, path: &str) -> Result<Option<Record>>;
+    async fn set_record(&self, uid: &str, path: &str, record: &Record) -> Result<()>;
+    /// Set many records of one uid at once.
+    async fn set_records(&self, uid: &str, items: &[(&str, Record)]) -> Result<()> {
+        for (path, record) in items {
+            self.set_record(uid, path, record).await?;
+        }
+        Ok(())
+    }
+    async fn get(&self, uid: &str, path: &str) -> Result<Option<(String, String)>> {
+        let record = self.get_record(uid, path).await?;
+        Ok(record.map(|r| (r.version, r.latest)))
+    }
+    async fn set(&self, uid: &str, path: &str, version: &str, latest: &str) -> Result<()> {
+        let record = Record {
+            version: version.to_string(),
+            latest: latest.to_string(),
+            ..Default::default()
+        };
+        self.set_record(uid, path, &record).await
+    }
     async fn del(&self, uid: &str, path: &str) -> Result<()>;
+    /// All records, of `uid` only if given, whose path starts with `prefix`.
+    async fn list(&self, uid: Option<&str>, prefix: &str) -> Result<Vec<CacheEntry>>;
+}
+
+/// How a failed write to a replica of [`MultiDB`] is handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// The write fails.
+    #[default]
+    WriteThrough,
+    /// The failure is logged and the write goes on, e.g. for a store on a device that may be
+    /// offline.
+    BestEffort,
 }
 
+/// Databases tried in order: the first one is the primary, the others are replicas.
+///
+/// Reads return the first database that has the record, writes go to all of them.
 #[derive(Default)]
 pub struct MultiDB {
-    dbs: Vec<Box<dyn DB + Sync + Send>>,
+    dbs: Vec<(Box<dyn DB + Sync + Send>, WritePolicy)>,
     dir: Option<std::path::PathBuf>,
 }
 
@@ -23,14 +253,30 @@ impl MultiDB {
     }
 
     pub fn add_db<C: DB + Sync + Send + 'static>(&mut self, db: C) {
-        self.dbs.push(Box::new(db));
+        self.add_replica(db, WritePolicy::WriteThrough);
+    }
+
+    /// Add `db` after the others, the policy doesn't apply if it's the first.
+    pub fn add_replica<C: DB + Sync + Send + 'static>(&mut self, db: C, policy: WritePolicy) {
+        self.dbs.push((Box::new(db), policy));
     }
 
     pub fn add_sqlite(&mut self, db_path: impl AsRef<std::path::Path>) -> Result<()> {
-        self.dbs.push(Box::new(Sqlite::new(db_path)?));
+        self.add_db(Sqlite::new(db_path)?);
         Ok(())
     }
 
+    /// Handle the result of a write to the `i`th database.
+    fn written(&self, i: usize, policy: WritePolicy, res: Result<()>) -> Result<()> {
+        match res {
+            Err(e) if i > 0 && policy == WritePolicy::BestEffort => {
+                warn!("Error writing replica db {}: {}", i, e);
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
     pub fn set_dir(&mut self, dir: std::path::PathBuf) {
         self.dir = Some(dir);
     }
@@ -54,8 +300,13 @@ impl MultiDB {
         Ok(Some((version, latest)))
     }
     pub async fn get(&self, uid: &str, path: &str) -> Result<Option<(String, String)>> {
-        for db in &self.dbs {
-            match db.get(uid, path).await {
+        let record = self.get_record(uid, path).await?;
+        Ok(record.map(|r| (r.version, r.latest)))
+    }
+
+    pub async fn get_record(&self, uid: &str, path: &str) -> Result<Option<Record>> {
+        for (db, _) in &self.dbs {
+            match db.get_record(uid, path).await {
                 Ok(Some(result)) => return Ok(Some(result)),
                 Ok(None) => continue,
                 Err(e) => {
@@ -67,16 +318,160 @@ impl MultiDB {
     }
 
     pub async fn set(&self, uid: &str, path: &str, version: &str, latest: &str) -> Result<()> {
-        for db in &self.dbs {
-            db.set(uid, path, version, latest).await?;
+        for (i, (db, policy)) in self.dbs.iter().enumerate() {
+            self.written(i, *policy, db.set(uid, path, version, latest).await)?;
+        }
+        Ok(())
+    }
+
+    pub async fn set_record(&self, uid: &str, path: &str, record: &Record) -> Result<()> {
+        for (i, (db, policy)) in self.dbs.iter().enumerate() {
+            self.written(i, *policy, db.set_record(uid, path, record).await)?;
+        }
+        Ok(())
+    }
+
+    pub async fn set_records(&self, uid: &str, items: &[(&str, Record)]) -> Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        for (i, (db, policy)) in self.dbs.iter().enumerate() {
+            self.written(i, *policy, db.set_records(uid, items).await)?;
         }
         Ok(())
     }
 
     pub async fn del(&self, uid: &str, path: &str) -> Result<()> {
-        for db in &self.dbs {
-            db.del(uid, path).await?;
+        for (i, (db, policy)) in self.dbs.iter().enumerate() {
+            self.written(i, *policy, db.del(uid, path).await)?;
         }
         Ok(())
     }
+
+    /// Records of all databases, of `uid` only if given, whose path starts with `prefix`.
+    ///
+    /// A record in more than one database is taken from the first, like [`get`](Self::get).
+    pub async fn list(&self, uid: Option<&str>, prefix: &str) -> Result<Vec<CacheEntry>> {
+        let mut entries = std::collections::BTreeMap::new();
+        for (db, _) in &self.dbs {
+            match db.list(uid, prefix).await {
+                Ok(list) => {
+                    for entry in list {
+                        let key = (entry.uid.clone(), entry.path.clone());
+                        entries.entry(key).or_insert(entry);
+                    }
+                }
+                Err(e) => warn!("Error listing db for uid: {:?}: {}", uid, e),
+            }
+        }
+        Ok(entries.into_values().collect())
+    }
+
+    /// Export the records [`list`](Self::list) returns as a JSON array.
+    pub async fn export_json(&self, uid: Option<&str>, prefix: &str) -> Result<String> {
+        to_json_array(&self.list(uid, prefix).await?)
+    }
+
+    /// Import records from [`export_json`](Self::export_json), returning how many were set.
+    pub async fn import_json(&self, json: &str) -> Result<usize> {
+        let entries = from_json_array(json)?;
+        let count = entries.len();
+        let mut by_uid = std::collections::BTreeMap::<_, Vec<_>>::new();
+        for entry in entries {
+            by_uid
+                .entry(entry.uid)
+                .or_default()
+                .push((entry.path, entry.record));
+        }
+        for (uid, records) in &by_uid {
+            let records = records
+                .iter()
+                .map(|(path, record)| (path.as_str(), record.clone()))
+                .collect::<Vec<_>>();
+            self.set_records(uid, &records).await?;
+        }
+        Ok(count)
+    }
+
+    /// Remove the records written more than `max_age` ago, returning them.
+    ///
+    /// Records without a sync time, e.g. from before it was recorded, are kept.
+    pub async fn prune_older(&self, max_age: std::time::Duration) -> Result<Vec<CacheEntry>> {
+        let deadline = now().saturating_sub(max_age.as_secs() as i64);
+        let mut removed = Vec::new();
+        for entry in self.list(None, "").await? {
+            if entry.record.synced_at.is_some_and(|t| t < deadline) {
+                self.del(&entry.uid, &entry.path).await?;
+                removed.push(entry);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn export_import() {
+        let mut db = MultiDB::default();
+        db.add_db(Sqlite::memory().unwrap());
+        let old = Record {
+            synced_at: Some(1),
+            meta: Some(r#"{"src":"/a \"b\""}"#.to_string()),
+            ..Record::new(OpKind::Sync, "1", "2")
+        };
+        db.set_record("d0", "/a/b", &old).await.unwrap();
+        db.set("d0", "/c", "3", "4").await.unwrap();
+        db.set("d1", "/a/b", "5", "6").await.unwrap();
+        assert_eq!(db.list(Some("d0"), "/a/").await.unwrap().len(), 1);
+        assert_eq!(db.list(None, "/a").await.unwrap().len(), 2);
+        let json = db.export_json(None, "").await.unwrap();
+
+        let mut copy = MultiDB::default();
+        copy.add_db(Sqlite::memory().unwrap());
+        assert_eq!(copy.import_json(&json).await.unwrap(), 3);
+        assert_eq!(
+            copy.list(None, "").await.unwrap(),
+            db.list(None, "").await.unwrap()
+        );
+
+        let removed = copy
+            .prune_older(std::time::Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].record, old);
+        assert_eq!(copy.list(None, "").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn replicas() {
+        let dir = tempfile::tempdir().unwrap();
+        // a file created where the store's directory should be makes every write fail
+        let broken = async |name: &str| {
+            let db = FileDb::open(dir.path().join(name).join("cache.json"))
+                .await
+                .unwrap();
+            std::fs::write(dir.path().join(name), "").unwrap();
+            db
+        };
+
+        let mut db = MultiDB::default();
+        db.add_db(MemoryDb::new());
+        db.add_replica(broken("a").await, WritePolicy::BestEffort);
+        db.set("d", "/a", "1", "2").await.unwrap();
+        assert_eq!(
+            db.get("d", "/a").await.unwrap(),
+            Some(("1".into(), "2".into()))
+        );
+
+        db.add_replica(broken("b").await, WritePolicy::WriteThrough);
+        assert!(db.set("d", "/b", "1", "2").await.is_err());
+
+        let mut db = MultiDB::default();
+        db.add_replica(broken("c").await, WritePolicy::BestEffort);
+        assert!(db.del("d", "/a").await.is_err());
+    }
 }
@@ -0,0 +1,290 @@
+//! Databases kept in one JSON or TOML file, locally or on a device.
+//!
+//! The records are held in memory and served from there. Reads reload the file once its
+//! modification time or size changed, and every write reloads it first, so several controllers
+//! sharing the same file pick up each other's records; writes that race each other can still
+//! lose one side.
+use std::{path::PathBuf, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{Mutex, MutexGuard},
+};
+use tracing::{debug, info};
+
+use super::{
+    CacheEntry, Record, StoredEntry,
+    dev::*,
+    from_json_array,
+    memory::{self, Table},
+    to_json_array,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// TOML for a `.toml` extension, JSON otherwise.
+    pub fn detect(path: &str) -> Self {
+        if path.ends_with(".toml") {
+            Format::Toml
+        } else {
+            Format::Json
+        }
+    }
+    fn parse(self, text: &str) -> Result<Table> {
+        let entries = match self {
+            Format::Json => from_json_array(text)?,
+            Format::Toml => toml::from_str::<TomlFile>(text)?
+                .entry
+                .into_iter()
+                .map(CacheEntry::try_from)
+                .collect::<Result<_>>()?,
+        };
+        Ok(memory::from_entries(entries))
+    }
+    fn render(self, table: &Table) -> Result<String> {
+        let entries = memory::list(table, None, "");
+        Ok(match self {
+            Format::Json => to_json_array(&entries)?,
+            Format::Toml => toml::to_string(&TomlFile {
+                entry: entries.into_iter().map(StoredEntry::from).collect(),
+            })?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct TomlFile {
+    #[serde(default)]
+    entry: Vec<StoredEntry>,
+}
+
+/// Where the file lives.
+enum Store {
+    Local(PathBuf),
+    User { user: User, path: U8PathBuf },
+}
+
+/// The modification time and size of the file, to tell whether it changed since it was loaded.
+type Stamp = (SystemTime, u64);
+
+impl Store {
+    /// The stamp of the file, `None` if it doesn't exist.
+    async fn stamp(&self) -> Result<Option<Stamp>> {
+        match self {
+            Store::Local(path) => match tokio::fs::metadata(path).await {
+                Ok(meta) => Ok(Some((meta.modified()?, meta.len()))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Store::User { user, path } => {
+                let Some(attr) = user.file_attributes(path).await?.1 else {
+                    return Ok(None);
+                };
+                let mtime = std::time::Duration::from_secs(attr.mtime.unwrap_or(0).into());
+                Ok(Some((
+                    SystemTime::UNIX_EPOCH + mtime,
+                    attr.size.unwrap_or(0),
+                )))
+            }
+        }
+    }
+    async fn load(&self) -> Result<Option<String>> {
+        match self {
+            Store::Local(path) => match tokio::fs::read_to_string(path).await {
+                Ok(text) => Ok(Some(text)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Store::User { user, path } => {
+                if !user.exist(path).await? {
+                    return Ok(None);
+                }
+                let mut file = user.open(path, OpenFlags::READ).await?;
+                let mut text = String::new();
+                file.read_to_string(&mut text).await?;
+                Ok(Some(text))
+            }
+        }
+    }
+    /// Replace the file through a temporary one next to it.
+    async fn save(&self, text: &str) -> Result<()> {
+        match self {
+            Store::Local(path) => {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let tmp = format!("{}.dvtmp", path.display());
+                tokio::fs::write(&tmp, text).await?;
+                tokio::fs::rename(&tmp, path).await?;
+            }
+            Store::User { user, path } => {
+                let tmp = U8PathBuf::from(format!("{path}.dvtmp"));
+                let mut file = user
+                    .open(
+                        &tmp,
+                        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                    )
+                    .await?;
+                file.write_all(text.as_bytes()).await?;
+                file.shutdown().await?;
+                user.rename(&tmp, path).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The records with the stamp of the file they were loaded from.
+#[derive(Default)]
+struct Loaded {
+    table: Table,
+    stamp: Option<Stamp>,
+}
+
+pub struct FileDb {
+    store: Store,
+    format: Format,
+    loaded: Mutex<Loaded>,
+}
+
+impl FileDb {
+    async fn new(store: Store, format: Format) -> Result<Self> {
+        let db = Self {
+            store,
+            format,
+            loaded: Mutex::default(),
+        };
+        let table = db.table().await?;
+        info!("file db loaded {} records", table.table.len());
+        drop(table);
+        Ok(db)
+    }
+    /// Open the local file at `path`, its format is detected from the extension.
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let format = Format::detect(&path.to_string_lossy());
+        Self::new(Store::Local(path), format).await
+    }
+    /// Open the file at `path` of `user`, e.g. on a device several controllers can reach.
+    pub async fn open_on(user: User, path: impl Into<U8PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let format = Format::detect(path.as_str());
+        Self::new(Store::User { user, path }, format).await
+    }
+    /// The records, reloaded if the file changed since they were loaded.
+    async fn table(&self) -> Result<MutexGuard<'_, Loaded>> {
+        let mut loaded = self.loaded.lock().await;
+        let stamp = self.store.stamp().await?;
+        if stamp != loaded.stamp {
+            debug!("file db changed, reload");
+            loaded.table = match self.store.load().await? {
+                Some(text) => self.format.parse(&text)?,
+                None => Table::new(),
+            };
+            loaded.stamp = stamp;
+        }
+        Ok(loaded)
+    }
+    /// Reload the file, apply `f` and write it back.
+    async fn update(&self, f: impl FnOnce(&mut Table) + Send) -> Result<()> {
+        let mut loaded = self.loaded.lock().await;
+        if let Some(text) = self.store.load().await? {
+            loaded.table = self.format.parse(&text)?;
+        }
+        f(&mut loaded.table);
+        let text = self.format.render(&loaded.table)?;
+        debug!("file db write {} records", loaded.table.len());
+        self.store.save(&text).await?;
+        loaded.stamp = self.store.stamp().await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl super::DB for FileDb {
+    async fn get_record(&self, uid: &str, path: &str) -> Result<Option<Record>> {
+        let loaded = self.table().await?;
+        Ok(loaded
+            .table
+            .get(&(uid.to_string(), path.to_string()))
+            .cloned())
+    }
+    async fn set_record(&self, uid: &str, path: &str, record: &Record) -> Result<()> {
+        let record = record.clone();
+        self.update(|table| {
+            table.insert((uid.to_string(), path.to_string()), record);
+        })
+        .await
+    }
+    async fn set_records(&self, uid: &str, items: &[(&str, Record)]) -> Result<()> {
+        self.update(|table| {
+            for (path, record) in items {
+                table.insert((uid.to_string(), path.to_string()), record.clone());
+            }
+        })
+        .await
+    }
+    async fn del(&self, uid: &str, path: &str) -> Result<()> {
+        self.update(|table| memory::del(table, uid, path)).await
+    }
+    async fn list(&self, uid: Option<&str>, prefix: &str) -> Result<Vec<CacheEntry>> {
+        Ok(memory::list(&self.table().await?.table, uid, prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OpKind, db::DB};
+    use dv_api::multi::Config;
+
+    async fn round_trip(db: impl AsyncFn() -> FileDb) {
+        let record = Record {
+            size: Some(3),
+            meta: Some(r#"{"a":1}"#.to_string()),
+            ..Record::new(OpKind::Sync, "1", "2")
+        };
+        let first = db().await;
+        first.set_record("d", "/a", &record).await.unwrap();
+        first.set("d", "/b", "3", "4").await.unwrap();
+        // a second controller sees the records and keeps them when writing
+        let second = db().await;
+        assert_eq!(second.get_record("d", "/a").await.unwrap(), Some(record));
+        second.del("d", "/b").await.unwrap();
+        // and the first sees the change without writing
+        assert_eq!(first.get_record("d", "/b").await.unwrap(), None);
+        first.set("e", "/c", "5", "6").await.unwrap();
+        let entries = db().await.list(None, "").await.unwrap();
+        let paths = entries.iter().map(|e| e.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["/a", "/c"]);
+    }
+
+    #[tokio::test]
+    async fn local() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["cache.json", "cache.toml"] {
+            let path = dir.path().join("sub").join(name);
+            round_trip(async || FileDb::open(&path).await.unwrap()).await;
+        }
+        let text = std::fs::read_to_string(dir.path().join("sub/cache.toml")).unwrap();
+        assert!(text.contains("[[entry]]"));
+    }
+
+    #[tokio::test]
+    async fn on_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let user = async || {
+            let mut cfg = Config::default();
+            cfg.set("mount", dir.path().to_string_lossy());
+            User::local(cfg).await.unwrap()
+        };
+        round_trip(async || FileDb::open_on(user().await, "cache.json").await.unwrap()).await;
+        assert!(dir.path().join("cache.json").exists());
+    }
+}
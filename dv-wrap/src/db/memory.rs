@@ -0,0 +1,71 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use super::{CacheEntry, Record, dev::*};
+
+/// Records by device and path.
+pub(super) type Table = BTreeMap<(String, String), Record>;
+
+pub(super) fn list(table: &Table, uid: Option<&str>, prefix: &str) -> Vec<CacheEntry> {
+    table
+        .iter()
+        .filter(|((u, p), _)| uid.is_none_or(|uid| uid == u) && p.starts_with(prefix))
+        .map(|((uid, path), record)| CacheEntry {
+            uid: uid.clone(),
+            path: path.clone(),
+            record: record.clone(),
+        })
+        .collect()
+}
+
+/// Remove `path` of `uid`, or all records of `uid` if `path` is empty.
+pub(super) fn del(table: &mut Table, uid: &str, path: &str) {
+    if path.is_empty() {
+        table.retain(|(u, _), _| u != uid);
+    } else {
+        table.remove(&(uid.to_string(), path.to_string()));
+    }
+}
+
+pub(super) fn from_entries(entries: Vec<CacheEntry>) -> Table {
+    entries
+        .into_iter()
+        .map(|e| ((e.uid, e.path), e.record))
+        .collect()
+}
+
+/// A database that lives as long as the process, e.g. for tests or dry runs.
+#[derive(Debug, Default)]
+pub struct MemoryDb {
+    table: Mutex<Table>,
+}
+
+impl MemoryDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn table(&self) -> std::sync::MutexGuard<'_, Table> {
+        self.table.lock().expect("memory db poisoned")
+    }
+}
+
+#[async_trait::async_trait]
+impl super::DB for MemoryDb {
+    async fn get_record(&self, uid: &str, path: &str) -> Result<Option<Record>> {
+        Ok(self
+            .table()
+            .get(&(uid.to_string(), path.to_string()))
+            .cloned())
+    }
+    async fn set_record(&self, uid: &str, path: &str, record: &Record) -> Result<()> {
+        self.table()
+            .insert((uid.to_string(), path.to_string()), record.clone());
+        Ok(())
+    }
+    async fn del(&self, uid: &str, path: &str) -> Result<()> {
+        del(&mut self.table(), uid, path);
+        Ok(())
+    }
+    async fn list(&self, uid: Option<&str>, prefix: &str) -> Result<Vec<CacheEntry>> {
+        Ok(list(&self.table(), uid, prefix))
+    }
+}
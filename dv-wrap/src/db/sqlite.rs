@@ -1,18 +1,106 @@
-use super::dev::*;
+use super::{CacheEntry, Record, dev::*};
 use std::path::Path;
 use tokio::sync::Mutex;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+/// A schema change, applied in a transaction of its own.
+struct Migration {
+    sql: &'static str,
+    /// The oldest schema version that can still read the database after this migration.
+    compat: u32,
+}
+
+/// All migrations in order, the schema version of a database is the number applied to it.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        sql: "CREATE TABLE IF NOT EXISTS cache (
+        device TEXT NOT NULL,
+        key TEXT NOT NULL,
+        version TEXT NOT NULL,
+        latest TEXT NOT NULL,
+        PRIMARY KEY (device, key)
+    )",
+        compat: 1,
+    },
+    Migration {
+        sql: "ALTER TABLE cache ADD COLUMN hash TEXT;
+        ALTER TABLE cache ADD COLUMN size INTEGER;
+        ALTER TABLE cache ADD COLUMN mode INTEGER;
+        ALTER TABLE cache ADD COLUMN synced_at INTEGER;
+        ALTER TABLE cache ADD COLUMN kind TEXT;
+        ALTER TABLE cache ADD COLUMN meta TEXT;",
+        compat: 1,
+    },
+    Migration {
+        sql: "ALTER TABLE cache ADD COLUMN mtime INTEGER;
+        ALTER TABLE cache ADD COLUMN src_mtime INTEGER;",
+        compat: 1,
+    },
+];
+
+/// The schema version this build writes.
+const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// The `(version, compat)` of the schema, `(0, 0)` for a database from before versioning.
+fn schema_version(conn: &rusqlite::Connection) -> Result<(u32, u32)> {
+    let versioned: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master \
+         WHERE type = 'table' AND name = 'schema_version')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !versioned {
+        return Ok((0, 0));
+    }
+    let row = conn.query_row("SELECT version, compat FROM schema_version", [], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    });
+    match row {
+        Ok(v) => Ok(v),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok((0, 0)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Apply the pending migrations to `conn`, each one in its own transaction.
+fn migrate(conn: &mut rusqlite::Connection, from: u32) -> Result<()> {
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        let version = version as u32 + 1;
+        info!("migrate sqlite schema to version {version}");
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+                version INTEGER NOT NULL,
+                compat INTEGER NOT NULL
+            );
+            DELETE FROM schema_version;",
+        )?;
+        tx.execute(
+            "INSERT INTO schema_version (version, compat) VALUES (?, ?)",
+            [version, migration.compat],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
 
 #[derive(Debug)]
 pub struct Sqlite {
     conn: Mutex<rusqlite::Connection>,
+    /// Set for a database written by a newer version that this one can still read.
+    read_only: bool,
 }
 
 impl Sqlite {
+    /// Open the database at `db_path` and bring its schema up to date.
+    ///
+    /// The file is copied to `<db_path>.v<version>.bak` before migrating it. A database of a
+    /// newer schema is opened read-only if this version can still read it, otherwise it fails.
     pub fn new(db_path: impl AsRef<Path>) -> Result<Self> {
         let db_path = db_path.as_ref();
         info!("use sqlite path {}", db_path.display());
-        let conn = match rusqlite::Connection::open(db_path) {
+        let mut conn = match rusqlite::Connection::open(db_path) {
             Ok(c) => c,
             Err(e) => {
                 if let Some(rusqlite::ErrorCode::CannotOpen) = e.sqlite_error_code() {
@@ -24,47 +112,124 @@ impl Sqlite {
                 rusqlite::Connection::open(db_path)?
             }
         };
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS cache (
-                device TEXT NOT NULL,
-                key TEXT NOT NULL,
-                version TEXT NOT NULL,
-                latest TEXT NOT NULL,
-                PRIMARY KEY (device, key)
-            )",
-            [],
-        )?;
+        let (version, compat) = schema_version(&conn)?;
+        if version > SCHEMA_VERSION {
+            if compat > SCHEMA_VERSION {
+                anyhow::bail!(
+                    "sqlite db {} has schema version {version}, \
+                     this version only reads up to {SCHEMA_VERSION}",
+                    db_path.display()
+                );
+            }
+            warn!(
+                "sqlite db {} has newer schema version {version}, open it read-only",
+                db_path.display()
+            );
+            drop(conn);
+            let conn = rusqlite::Connection::open_with_flags(
+                db_path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )?;
+            return Ok(Self {
+                conn: Mutex::new(conn),
+                read_only: true,
+            });
+        }
+        if version < SCHEMA_VERSION {
+            let empty: bool = conn.query_row(
+                "SELECT NOT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table')",
+                [],
+                |row| row.get(0),
+            )?;
+            if !empty {
+                let backup = format!("{}.v{version}.bak", db_path.display());
+                info!("back up sqlite db to {backup}");
+                match std::fs::remove_file(&backup) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e)?,
+                    _ => {}
+                }
+                conn.execute("VACUUM INTO ?", [&backup])?;
+            }
+            migrate(&mut conn, version)?;
+        }
         info!("sqlite db initialized");
         Ok(Self {
             conn: Mutex::new(conn),
+            read_only: false,
         })
     }
-    #[cfg(test)]
+    /// An in-memory database, its records are gone once it is dropped.
     pub fn memory() -> Result<Self> {
-        let conn = rusqlite::Connection::open_in_memory()?;
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS cache (
-                device TEXT NOT NULL,
-                key TEXT NOT NULL,
-                version TEXT NOT NULL,
-                latest TEXT NOT NULL,
-                PRIMARY KEY (device, key)
-            )",
-            [],
-        )?;
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        migrate(&mut conn, 0)?;
         Ok(Self {
             conn: Mutex::new(conn),
+            read_only: false,
         })
     }
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            anyhow::bail!("sqlite db has a newer schema and is read-only");
+        }
+        Ok(())
+    }
+}
+
+const UPSERT: &str = "INSERT OR REPLACE INTO cache
+    (device, key, version, latest, hash, size, mode, mtime, src_mtime, synced_at, kind, meta)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+
+fn upsert(
+    stmt: &mut rusqlite::CachedStatement<'_>,
+    uid: &str,
+    key: &str,
+    r: &Record,
+) -> Result<()> {
+    stmt.execute(rusqlite::params![
+        uid,
+        key,
+        r.version,
+        r.latest,
+        r.hash,
+        r.size.map(|s| s as i64),
+        r.mode,
+        r.mtime,
+        r.src_mtime,
+        r.synced_at,
+        r.kind.map(|k| k.to_string()),
+        r.meta,
+    ])?;
+    Ok(())
+}
+
+/// The columns [`record`] reads, in order.
+const RECORD: &str = "version, latest, hash, size, mode, mtime, src_mtime, synced_at, kind, meta";
+
+/// Read the [`RECORD`] columns starting at column `at`.
+fn record(row: &rusqlite::Row<'_>, at: usize) -> rusqlite::Result<Record> {
+    Ok(Record {
+        version: row.get(at)?,
+        latest: row.get(at + 1)?,
+        hash: row.get(at + 2)?,
+        size: row.get::<_, Option<i64>>(at + 3)?.map(|s| s as u64),
+        mode: row.get(at + 4)?,
+        mtime: row.get(at + 5)?,
+        src_mtime: row.get(at + 6)?,
+        synced_at: row.get(at + 7)?,
+        kind: row
+            .get::<_, Option<String>>(at + 8)?
+            .and_then(|k| k.parse().ok()),
+        meta: row.get(at + 9)?,
+    })
 }
 
 #[async_trait::async_trait]
 impl crate::db::DB for Sqlite {
-    async fn get(&self, uid: &str, key: &str) -> Result<Option<(String, String)>> {
+    async fn get_record(&self, uid: &str, key: &str) -> Result<Option<Record>> {
         let row = self.conn.lock().await.query_row(
-            "SELECT version, latest FROM cache WHERE device = ? AND key = ?",
+            &format!("SELECT {RECORD} FROM cache WHERE device = ? AND key = ?"),
             [uid, key],
-            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            |row| record(row, 0),
         );
         match row {
             Ok(s) => Ok(Some(s)),
@@ -72,20 +237,29 @@ impl crate::db::DB for Sqlite {
             Err(e) => anyhow::bail!(e),
         }
     }
-    async fn set(&self, uid: &str, key: &str, version: &str, latest: &str) -> Result<()> {
-        debug!("cache set: {} {} {} {}", uid, key, version, latest);
-        Ok(self
-            .conn
-            .lock()
-            .await
-            .execute(
-                "INSERT OR REPLACE INTO cache (device, key, version, latest) VALUES (?, ?, ?, ?)",
-                [uid, key, version, latest],
-            )
-            .map(|_| ())?)
+    async fn set_record(&self, uid: &str, key: &str, record: &Record) -> Result<()> {
+        debug!("cache set: {} {} {:?}", uid, key, record);
+        self.check_writable()?;
+        let conn = self.conn.lock().await;
+        upsert(&mut conn.prepare_cached(UPSERT)?, uid, key, record)
+    }
+    async fn set_records(&self, uid: &str, items: &[(&str, Record)]) -> Result<()> {
+        debug!("cache set batch: {} {} items", uid, items.len());
+        self.check_writable()?;
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(UPSERT)?;
+            for (key, record) in items {
+                upsert(&mut stmt, uid, key, record)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
     }
     async fn del(&self, uid: &str, key: &str) -> Result<()> {
         info!("cache del: {} {}", uid, key);
+        self.check_writable()?;
         let conn = self.conn.lock().await;
         if !key.is_empty() {
             conn.execute("DELETE FROM cache WHERE device = ? AND key = ?", [uid, key])
@@ -96,4 +270,109 @@ impl crate::db::DB for Sqlite {
         }?;
         Ok(())
     }
+    async fn list(&self, uid: Option<&str>, prefix: &str) -> Result<Vec<CacheEntry>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT device, key, {RECORD} FROM cache
+            WHERE (?1 IS NULL OR device = ?1) AND substr(key, 1, length(?2)) = ?2
+            ORDER BY device, key"
+        ))?;
+        let rows = stmt.query_map(rusqlite::params![uid, prefix], |row| {
+            Ok(CacheEntry {
+                uid: row.get(0)?,
+                path: row.get(1)?,
+                record: record(row, 2)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DB;
+
+    #[tokio::test]
+    async fn legacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE cache (
+                device TEXT NOT NULL,
+                key TEXT NOT NULL,
+                version TEXT NOT NULL,
+                latest TEXT NOT NULL,
+                PRIMARY KEY (device, key)
+            );
+            INSERT INTO cache VALUES ('d', 'k', '1', '2');",
+        )
+        .unwrap();
+        drop(conn);
+        let db = Sqlite::new(&path).unwrap();
+        assert_eq!(
+            db.get("d", "k").await.unwrap(),
+            Some(("1".to_string(), "2".to_string()))
+        );
+        let conn = db.conn.lock().await;
+        assert_eq!(schema_version(&conn).unwrap().0, SCHEMA_VERSION);
+        assert!(dir.path().join("cache.db.v0.bak").exists());
+        drop(conn);
+        drop(db);
+        // an up to date database is opened without another backup
+        std::fs::remove_file(dir.path().join("cache.db.v0.bak")).unwrap();
+        Sqlite::new(&path).unwrap();
+        assert!(
+            !dir.path()
+                .join(format!("cache.db.v{SCHEMA_VERSION}.bak"))
+                .exists()
+        );
+    }
+
+    #[tokio::test]
+    async fn newer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let db = Sqlite::new(&path).unwrap();
+        db.set("d", "k", "1", "2").await.unwrap();
+        drop(db);
+        let set_version = |version: u32, compat: u32| {
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.execute(
+                "UPDATE schema_version SET version = ?, compat = ?",
+                [version, compat],
+            )
+            .unwrap();
+        };
+        set_version(SCHEMA_VERSION + 1, SCHEMA_VERSION);
+        let db = Sqlite::new(&path).unwrap();
+        assert!(db.get("d", "k").await.unwrap().is_some());
+        assert!(db.set("d", "k", "3", "4").await.is_err());
+        drop(db);
+        set_version(SCHEMA_VERSION + 1, SCHEMA_VERSION + 1);
+        assert!(Sqlite::new(&path).is_err());
+    }
+
+    #[tokio::test]
+    async fn record() {
+        use crate::db::OpKind;
+        let db = Sqlite::memory().unwrap();
+        let record = Record {
+            hash: Some("sha256:00".to_string()),
+            size: Some(3),
+            mode: Some(0o644),
+            meta: Some(r#"{"etag":"x"}"#.to_string()),
+            ..Record::new(OpKind::Download, "1", "2")
+        };
+        db.set_record("d", "k", &record).await.unwrap();
+        assert_eq!(db.get_record("d", "k").await.unwrap(), Some(record));
+        assert_eq!(
+            db.get("d", "k").await.unwrap(),
+            Some(("1".to_string(), "2".to_string()))
+        );
+        db.set("d", "k", "3", "4").await.unwrap();
+        let record = db.get_record("d", "k").await.unwrap().unwrap();
+        assert_eq!((record.kind, record.size), (None, None));
+    }
 }
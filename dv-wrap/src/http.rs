@@ -0,0 +1,313 @@
+//! The HTTP client shared by downloads through the [`Context`](crate::Context).
+//!
+//! Requests that fail to connect, time out or get a 5xx response are retried with exponential
+//! backoff. The `HTTPS_PROXY`/`HTTP_PROXY`/`NO_PROXY` variables are honoured unless a proxy is
+//! configured, which still skips the `NO_PROXY` hosts.
+use std::{path::PathBuf, time::Duration};
+
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use tracing::{debug, warn};
+
+use crate::dev::*;
+
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    /// The longest wait for the next bytes of a response.
+    pub read_timeout: Duration,
+    /// The limit of a whole request including its body, none by default since downloads vary.
+    pub timeout: Option<Duration>,
+    /// How many times a failed request is retried.
+    pub retries: u32,
+    /// The delay before the first retry, doubled for each further one up to `max_backoff`.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// A proxy url for all requests, instead of the one from the environment.
+    pub proxy: Option<String>,
+    /// PEM files of additional trusted certificates.
+    pub ca_certs: Vec<PathBuf>,
+    pub headers: Vec<(String, String)>,
+    /// A token sent as `Authorization: Bearer <token>`.
+    pub bearer: Option<String>,
+    pub user_agent: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            timeout: None,
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            proxy: None,
+            ca_certs: Vec::new(),
+            headers: Vec::new(),
+            bearer: None,
+            user_agent: concat!("dv-wrap/", env!("CARGO_PKG_VERSION")).to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new(&HttpConfig::default()).expect("default http client")
+    }
+}
+
+impl HttpClient {
+    pub fn new(cfg: &HttpConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &cfg.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        if let Some(token) = &cfg.bearer {
+            let mut value = HeaderValue::from_str(&format!("Bearer {token}"))?;
+            value.set_sensitive(true);
+            headers.insert(header::AUTHORIZATION, value);
+        }
+        let mut builder = reqwest::Client::builder()
+            .user_agent(&cfg.user_agent)
+            .default_headers(headers)
+            .connect_timeout(cfg.connect_timeout)
+            .read_timeout(cfg.read_timeout);
+        if let Some(timeout) = cfg.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = &cfg.proxy {
+            let proxy = reqwest::Proxy::all(proxy)?.no_proxy(reqwest::NoProxy::from_env());
+            builder = builder.proxy(proxy);
+        }
+        for path in &cfg.ca_certs {
+            let pem = std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("read ca cert {}: {e}", path.display()))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        Ok(Self {
+            client: builder.build()?,
+            retries: cfg.retries,
+            backoff: cfg.backoff,
+            max_backoff: cfg.max_backoff,
+        })
+    }
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_backoff)
+    }
+    /// Send `req`, retrying on connection errors, timeouts and 5xx responses.
+    ///
+    /// The last response is returned once the retries are used up, whatever its status.
+    pub async fn execute(&self, req: reqwest::Request) -> Result<reqwest::Response> {
+        let mut attempt = 0;
+        loop {
+            let retry = (attempt < self.retries).then(|| req.try_clone()).flatten();
+            let url = req.url().clone();
+            let Some(next) = retry else {
+                return Ok(self.client.execute(req).await?);
+            };
+            let error = match self.client.execute(next).await {
+                Ok(resp) if !resp.status().is_server_error() => return Ok(resp),
+                Ok(resp) => resp.status().to_string(),
+                Err(e) if e.is_connect() || e.is_timeout() => e.to_string(),
+                Err(e) => return Err(e.into()),
+            };
+            let delay = self.delay(attempt);
+            warn!("request {url} failed: {error}, retry in {delay:?}");
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+    pub async fn get(&self, url: reqwest::Url) -> Result<reqwest::Response> {
+        debug!("get {url}");
+        self.execute(reqwest::Request::new(reqwest::Method::GET, url))
+            .await
+    }
+}
+
+/// A local HTTP/1.1 server standing in for real ones in tests.
+#[cfg(test)]
+pub(crate) mod stub {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[derive(Debug)]
+    pub struct Request {
+        pub method: String,
+        pub path: String,
+        pub headers: Vec<(String, String)>,
+    }
+
+    impl Request {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    pub struct Response {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl Response {
+        pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+            Self {
+                status,
+                headers: Vec::new(),
+                body: body.into(),
+            }
+        }
+        pub fn header(mut self, name: &str, value: impl ToString) -> Self {
+            self.headers.push((name.to_string(), value.to_string()));
+            self
+        }
+    }
+
+    /// Serve `handler` on a local port until the runtime stops, returning the base url.
+    pub async fn serve(handler: impl Fn(Request) -> Response + Send + Sync + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0; 1024];
+                    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                        }
+                    }
+                    let head = String::from_utf8_lossy(&buf).to_string();
+                    let mut lines = head.split("\r\n");
+                    let mut first = lines.next().unwrap_or_default().split(' ');
+                    let req = Request {
+                        method: first.next().unwrap_or_default().to_string(),
+                        path: first.next().unwrap_or_default().to_string(),
+                        headers: lines
+                            .filter_map(|l| l.split_once(": "))
+                            .map(|(k, v)| (k.to_string(), v.to_string()))
+                            .collect(),
+                    };
+                    let resp = handler(req);
+                    let mut out = format!(
+                        "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n",
+                        resp.status,
+                        resp.body.len()
+                    );
+                    for (k, v) in &resp.headers {
+                        out.push_str(&format!("{k}: {v}\r\n"));
+                    }
+                    out.push_str("\r\n");
+                    let _ = stream.write_all(out.as_bytes()).await;
+                    let _ = stream.write_all(&resp.body).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    };
+
+    use super::{stub::*, *};
+
+    fn config() -> HttpConfig {
+        HttpConfig {
+            backoff: Duration::from_millis(10),
+            headers: vec![("X-Test".into(), "1".into())],
+            bearer: Some("secret".into()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        let hits = Arc::new(AtomicU32::new(0));
+        let counter = hits.clone();
+        let base = serve(move |req| {
+            assert_eq!(req.header("authorization"), Some("Bearer secret"));
+            assert_eq!(req.header("x-test"), Some("1"));
+            assert_eq!(req.method, "GET");
+            match (req.path.as_str(), counter.fetch_add(1, Ordering::SeqCst)) {
+                ("/flaky", 0 | 1) => Response::new(503, ""),
+                ("/flaky", _) => Response::new(200, "ok").header("ETag", "\"1\""),
+                _ => Response::new(500, ""),
+            }
+        })
+        .await;
+        let client = HttpClient::new(&config()).unwrap();
+        let resp = client
+            .get(format!("{base}/flaky").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[header::ETAG], "\"1\"");
+        assert_eq!(resp.text().await.unwrap(), "ok");
+        assert_eq!(hits.swap(0, Ordering::SeqCst), 3);
+
+        // the last response is returned once the retries are used up
+        let resp = client
+            .get(format!("{base}/down").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 500);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        // bind and drop to get a port nothing listens on
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = HttpClient::new(&HttpConfig {
+            retries: 2,
+            ..config()
+        })
+        .unwrap();
+        let url = format!("http://127.0.0.1:{port}/").parse().unwrap();
+        assert!(client.get(url).await.is_err());
+        assert!(
+            HttpClient::new(&HttpConfig {
+                ca_certs: vec!["/nonexistent.pem".into()],
+                ..config()
+            })
+            .is_err()
+        );
+    }
+}
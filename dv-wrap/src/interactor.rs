@@ -1,13 +1,18 @@
 use std::{
     collections::HashMap,
-    io::{Write, stdout},
-    time::Duration,
+    io::{IsTerminal, Write, stdout},
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
 use super::dev::*;
+use crate::progress::{Progress, human_bytes};
 use crossterm::{
+    cursor::MoveToColumn,
     event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
-    terminal::{disable_raw_mode, enable_raw_mode},
+    queue,
+    style::Print,
+    terminal::{Clear, ClearType, disable_raw_mode, enable_raw_mode},
 };
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -22,18 +27,69 @@ pub trait Interactor {
     /// - `msg`: The message to display to the user.
     /// - `opts`: The options to display to the user. For example, `["y/exec", "n/do nothing"]`.
     async fn confirm(&self, msg: String, opts: &[&str]) -> Result<usize>;
+    /// Render the progress of a running transfer. It is called periodically while the transfer
+    /// runs and once more with `finished` set when it ends. Nothing is shown by default.
+    async fn progress(&self, _progress: &Progress) {}
 }
 
 pub type DynInteractor = dyn Interactor + Sync;
 
+/// How often progress is logged when stdout is not a terminal.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Debug)]
-pub struct TermInteractor {}
+pub struct TermInteractor {
+    tty: bool,
+    last_progress_log: Mutex<Option<Instant>>,
+}
 
 impl TermInteractor {
     pub fn new() -> std::io::Result<Self> {
         setup_stdin_nonblock()?;
-        Ok(Self {})
+        Ok(Self {
+            tty: stdout().is_terminal(),
+            last_progress_log: Mutex::new(None),
+        })
+    }
+}
+
+fn progress_summary(p: &Progress) -> String {
+    let mut line = format!(
+        "{} {}/{} files, {}, {}/s",
+        p.title,
+        p.files_done,
+        p.files_total,
+        human_bytes(p.bytes_done as f64),
+        human_bytes(p.rate)
+    );
+    if let Some(f) = p.active.first() {
+        line.push_str(", ");
+        line.push_str(&f.path);
+        if let Some(total) = f.total.filter(|t| *t > 0) {
+            line.push_str(&format!(" {}%", f.done * 100 / total));
+        }
     }
+    line
+}
+
+fn progress_bar(p: &Progress, width: usize) -> String {
+    let frac = if p.finished || p.files_total == 0 {
+        1.0
+    } else {
+        let active: f64 = p
+            .active
+            .iter()
+            .filter_map(|f| f.total.filter(|t| *t > 0).map(|t| f.done as f64 / t as f64))
+            .sum();
+        ((p.files_done as f64 + active) / p.files_total as f64).min(1.0)
+    };
+    let filled = (frac * width as f64) as usize;
+    format!(
+        "[{}{}] {:>3}%",
+        "#".repeat(filled),
+        "-".repeat(width - filled),
+        (frac * 100.0) as usize
+    )
 }
 
 #[async_trait::async_trait]
@@ -147,6 +203,40 @@ impl Interactor for TermInteractor {
             }
         }
     }
+    async fn progress(&self, p: &Progress) {
+        if !self.tty {
+            let mut last = self
+                .last_progress_log
+                .lock()
+                .expect("progress log poisoned");
+            if p.finished || last.is_none_or(|t| t.elapsed() >= PROGRESS_LOG_INTERVAL) {
+                *last = (!p.finished).then(Instant::now);
+                println!("{}", progress_summary(p));
+            }
+            return;
+        }
+        let cols = crossterm::terminal::size().map_or(80, |(cols, _)| cols as usize);
+        let mut line = format!("{} {}", progress_bar(p, 20), progress_summary(p));
+        if let Some((i, _)) = line.char_indices().nth(cols.saturating_sub(1)) {
+            line.truncate(i);
+        }
+        let mut stdout = stdout();
+        let res = queue!(
+            stdout,
+            MoveToColumn(0),
+            Clear(ClearType::CurrentLine),
+            Print(line)
+        )
+        .and_then(|_| {
+            if p.finished {
+                writeln!(stdout)?;
+            }
+            stdout.flush()
+        });
+        if let Err(e) = res {
+            debug!("failed to draw progress: {e}");
+        }
+    }
 }
 
 struct RawModeGuard;
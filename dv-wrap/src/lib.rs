@@ -1,12 +1,21 @@
 mod db;
-pub use db::{MultiDB, Sqlite};
+pub use db::{CacheEntry, FileDb, MemoryDb, MultiDB, OpKind, Record, Sqlite, WritePolicy};
 
 mod context;
 pub use context::{AsRefContext, Context};
 
+mod http;
+pub use http::{HttpClient, HttpConfig};
+
 mod interactor;
 pub use interactor::TermInteractor;
 
+mod progress;
+pub use progress::{FileProgress, Progress};
+
+mod throttle;
+pub use throttle::{Limiter, RateLimit};
+
 mod user;
 pub use user::User;
 
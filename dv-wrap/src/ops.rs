@@ -11,13 +11,20 @@ pub use pm::Pm;
 mod once;
 pub use once::Once;
 mod refresh;
-pub use refresh::refresh;
+pub use refresh::{prune, refresh};
 mod fs;
 pub use fs::*;
 mod dl;
-pub use dl::Dl;
+pub use dl::cache as dl_cache;
+pub use dl::{
+    Algorithm as DigestAlgorithm, ArchiveFormat, Checksum, Digest, Dl, DlBatch, DlError, Extract,
+    Forge, Release, ReleaseAsset, deploy,
+};
 mod sync;
-pub use sync::{Entry as SyncEntry, Opt as SyncOpt, SyncContext};
+pub use sync::{
+    Compression as SyncCompression, Entry as SyncEntry, FanOut, FanOutPlan, FanOutResult,
+    Opt as SyncOpt, Policy as SyncPolicy, SyncContext,
+};
 
 mod dotutils;
 pub use dotutils::*;
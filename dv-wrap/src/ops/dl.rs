@@ -1,22 +1,85 @@
 use std::path::Path;
 
 use reqwest::header;
+use tokio::io::AsyncWriteExt;
 use tracing::{debug, info};
 
 use super::dev::*;
+use crate::{OpKind, RateLimit, Record, progress::Tracker};
+
+mod checksum;
+pub use checksum::{Algorithm, Checksum, Digest};
+mod extract;
+pub use extract::{ArchiveFormat, Extract};
+mod deploy;
+pub use deploy::deploy;
+mod freshness;
+use freshness::Freshness;
+mod batch;
+pub mod cache;
+pub use batch::DlBatch;
+mod release;
+pub use release::{Forge, Release, ReleaseAsset};
+mod source;
+use source::Source;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DlError {
+    #[error("checksum mismatch for {url}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        url: String,
+        expected: Digest,
+        actual: Digest,
+    },
+}
+
+/// Where a download comes from.
+enum Origin {
+    Http(reqwest::Request),
+    /// A file copied from the controller or a user, with its size and `<size>:<mtime>`.
+    Copy {
+        source: Source,
+        url: reqwest::Url,
+        size: u64,
+        validator: String,
+    },
+}
 
 pub struct Dl<C: AsRefContext> {
     ctx: C,
-    req: reqwest::Request,
+    origin: Origin,
     now: u64,
-    etag: Option<String>,
+    /// The record of the cached file, if it exists.
+    cached: Option<Record>,
+    limit: Option<RateLimit>,
+    checksum: Option<Checksum>,
+    /// The lock of the cached file, held until the download is stored.
+    lock: std::fs::File,
 }
 
 impl<C: AsRefContext> Dl<C> {
+    /// Prepare downloading `url` into the cache, `None` if the cached file can be used.
+    ///
+    /// The cached file is used for `expire` seconds after it was fetched, or as long as the
+    /// `Cache-Control` of its response allows if not given. After that it is revalidated with its
+    /// `ETag` and `Last-Modified`.
+    ///
+    /// Besides HTTP urls, `file://` paths on the controller and `user://<uid>/<path>` files of
+    /// the users in the context are copied into the cache. They are revalidated by their size
+    /// and mtime.
+    ///
+    /// With a `checksum` the download is verified before it replaces the cached file, which is
+    /// only reused if it was verified against the same digest, or for a checksum file against
+    /// one of the same algorithm.
+    ///
+    /// The cached file is locked from the lookup until the returned download is executed or
+    /// dropped, so processes sharing the cache dir download it once. Don't prepare the same url
+    /// again before that, it waits for the lock.
     pub async fn new<U: AsRef<str>>(
         ctx: C,
         url: U,
         expire: Option<u64>,
+        checksum: Option<Checksum>,
     ) -> Result<(String, Option<Self>)> {
         let c = ctx.as_ref();
         let Some(cdir) = &c.cache_dir else {
@@ -31,10 +94,24 @@ impl<C: AsRefContext> Dl<C> {
             .expect("Time went backwards")
             .as_secs();
 
-        use base64::Engine;
-        let name = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(url);
-        let path = cdir.join(&name).to_string_lossy().to_string();
-        let vl = c.db.get(&name, "").await?;
+        let path = cdir
+            .join(cache::file_name(url))
+            .to_string_lossy()
+            .to_string();
+        tokio::fs::create_dir_all(cdir).await?;
+        // another process may finish the download while this one waits
+        let lock = cache::lock(&path).await?;
+        let record = c.db.get_record(&path, "").await?;
+        let hash = record.as_ref().and_then(|r| r.hash.as_deref());
+        let pinned = match &checksum {
+            Some(Checksum::Digest(d)) => hash == Some(&d.to_string()),
+            // the checksum file is only fetched on a download, so a verified digest of the
+            // same algorithm has to do
+            Some(Checksum::File { algorithm, .. }) => hash
+                .and_then(|h| h.parse::<Digest>().ok())
+                .is_some_and(|d| d.algorithm == *algorithm),
+            None => true,
+        };
         let exsists = match tokio::fs::metadata(&path).await {
             Ok(m) if m.is_file() => true,
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
@@ -43,47 +120,212 @@ impl<C: AsRefContext> Dl<C> {
                 bail!("cache file {} exists but not a file", path)
             }
         };
-        if exsists
-            && let Some((version, _)) = &vl
-            && let (Ok(t), Some(expire)) = (version.parse::<u64>(), expire)
-            && now - t < expire
+        let cached = record.filter(|_| exsists && pinned);
+        if let Some(record) = &cached
+            && let Ok(fetched) = record.version.parse::<u64>()
+            && match expire {
+                Some(expire) => now.saturating_sub(fetched) < expire,
+                None => Freshness::from_meta(record.meta.as_deref()).fresh(fetched, now),
+            }
         {
             info!("cache hit {path} for url: {url}");
+            Self::used(&c, &path, record, now).await?;
             return Ok((path, None));
         }
+        if let Some(source) = Source::parse(&url2)? {
+            let (size, validator) = source.stat(&c).await?;
+            if let Some(record) = &cached
+                && record.latest == validator
+            {
+                info!("{url} unchanged, use cache {path}");
+                Self::used(&c, &path, record, now).await?;
+                return Ok((path, None));
+            }
+            let origin = Origin::Copy {
+                source,
+                url: url2,
+                size,
+                validator,
+            };
+            drop(c);
+            let dl = Self::with(ctx, origin, now, cached, checksum, lock);
+            return Ok((path, Some(dl)));
+        }
 
         let mut req = reqwest::Request::new(reqwest::Method::GET, url2);
-        req.headers_mut().insert(
-            header::USER_AGENT,
-            header::HeaderValue::from_static("dv-wrap/0.1"),
-        );
-
-        if exsists && let Some((_, e)) = &vl {
-            req.headers_mut().insert(
-                header::IF_NONE_MATCH,
-                header::HeaderValue::from_str(e).unwrap(),
-            );
+        if let Some(record) = &cached {
+            let freshness = Freshness::from_meta(record.meta.as_deref());
+            let headers = req.headers_mut();
+            if !freshness.no_store {
+                if !record.latest.is_empty() {
+                    headers.insert(
+                        header::IF_NONE_MATCH,
+                        header::HeaderValue::from_str(&record.latest)?,
+                    );
+                }
+                if let Some(date) = &freshness.last_modified {
+                    headers.insert(
+                        header::IF_MODIFIED_SINCE,
+                        header::HeaderValue::from_str(date)?,
+                    );
+                }
+            }
         }
         drop(c);
-        Ok((
-            path,
-            Some(Self {
-                ctx,
-                req,
-                now,
-                etag: vl.map(|v| v.1),
-            }),
-        ))
+        let dl = Self::with(ctx, Origin::Http(req), now, cached, checksum, lock);
+        Ok((path, Some(dl)))
+    }
+    fn with(
+        ctx: C,
+        origin: Origin,
+        now: u64,
+        cached: Option<Record>,
+        checksum: Option<Checksum>,
+        lock: std::fs::File,
+    ) -> Self {
+        Self {
+            ctx,
+            origin,
+            now,
+            cached,
+            limit: None,
+            checksum,
+            lock,
+        }
+    }
+    /// Mark the cached file as used, the time of the last use orders the eviction of the cache.
+    async fn used(ctx: &Context, path: &str, record: &Record, now: u64) -> Result<()> {
+        let used = Record {
+            synced_at: Some(now as i64),
+            ..record.clone()
+        };
+        ctx.db.set_record(path, "", &used).await
+    }
+    /// Limit the bandwidth of the download.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.limit = limit;
     }
     pub async fn execute<P: AsRef<str>>(self, path: P) -> Result<()> {
-        let ctx = self.ctx.as_ref();
+        let Self {
+            ctx,
+            origin,
+            now,
+            cached,
+            limit,
+            checksum,
+            lock: _lock,
+        } = self;
+        let ctx = ctx.as_ref();
         let path = path.as_ref();
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(5))
-            .build()?;
-        let Ok(mut resp) = client.execute(self.req).await else {
-            bail!("failed to fetch url")
+        match origin {
+            Origin::Http(req) => {
+                let tracker = Tracker::new(format!("download {}", req.url()), 1).with_limit(limit);
+                let download = Self::download(&ctx, req, now, cached, checksum, path, &tracker);
+                tracker.run(&*ctx.interactor, download).await
+            }
+            Origin::Copy {
+                source,
+                url,
+                size,
+                validator,
+            } => {
+                let tracker = Tracker::new(format!("download {url}"), 1).with_limit(limit);
+                let copy = async {
+                    let expected = match &checksum {
+                        Some(checksum) => Some(checksum.resolve(&ctx.http, &url).await?),
+                        None => None,
+                    };
+                    let part = format!("{path}.dvpart");
+                    if let Some(parent) = Path::new(path).parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    let mut file = tokio::fs::File::create(&part).await?;
+                    let progress = tracker.file(path, Some(size));
+                    let from = source.open(&ctx).await?;
+                    tokio::io::copy(&mut progress.reader(from), &mut file).await?;
+                    drop(progress);
+                    file.flush().await?;
+                    drop(file);
+                    let record = Record {
+                        size: Some(size),
+                        ..Record::new(OpKind::Download, now.to_string(), validator)
+                    };
+                    Self::store(&ctx, path, url.as_str(), expected.as_ref(), record).await
+                };
+                tracker.run(&*ctx.interactor, copy).await
+            }
+        }
+    }
+    /// Verify the finished `<path>.dvpart` and move it over the cached file with its `record`.
+    async fn store(
+        ctx: &Context,
+        path: &str,
+        url: &str,
+        expected: Option<&Digest>,
+        record: Record,
+    ) -> Result<()> {
+        let part = format!("{path}.dvpart");
+        let hash = match expected {
+            Some(expected) => match checksum::verify(&part, expected, url).await {
+                Ok(actual) => Some(actual.to_string()),
+                Err(e) => {
+                    ctx.db.del(&part, "").await?;
+                    return Err(e);
+                }
+            },
+            None => None,
         };
+        tokio::fs::rename(&part, path).await?;
+        ctx.db.del(&part, "").await?;
+        debug!("downloaded to {}", path);
+        let record = Record { hash, ..record };
+        ctx.db.set_record(path, "", &record).await
+    }
+    async fn download(
+        ctx: &Context,
+        mut req: reqwest::Request,
+        now: u64,
+        cached: Option<Record>,
+        checksum: Option<Checksum>,
+        path: &str,
+        tracker: &Tracker,
+    ) -> Result<()> {
+        let expected = match &checksum {
+            Some(checksum) => Some(checksum.resolve(&ctx.http, req.url()).await?),
+            None => None,
+        };
+        let hash = cached.as_ref().and_then(|r| r.hash.clone());
+        if let Some(expected) = &expected
+            && hash != Some(expected.to_string())
+        {
+            // the cached file wasn't verified against this digest, so it can't be kept
+            req.headers_mut().remove(header::IF_NONE_MATCH);
+            req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+        }
+        let url = req.url().to_string();
+        let part = format!("{path}.dvpart");
+        let resume = match (
+            tokio::fs::metadata(&part).await,
+            ctx.db.get(&part, "").await?,
+        ) {
+            (Ok(m), Some((validator, _))) if m.len() > 0 => Some((m.len(), validator)),
+            _ => None,
+        };
+        if let Some((len, validator)) = &resume {
+            debug!("try to resume {} from {}", part, len);
+            let headers = req.headers_mut();
+            headers.insert(
+                header::RANGE,
+                header::HeaderValue::from_str(&format!("bytes={len}-"))?,
+            );
+            headers.insert(header::IF_RANGE, header::HeaderValue::from_str(validator)?);
+        }
+        let mut resp = ctx
+            .http
+            .execute(req)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to fetch {url}: {e}"))?;
+        let freshness = Freshness::from_response(&resp);
         if resp.status().is_success() {
             let etag = resp
                 .headers()
@@ -92,9 +334,35 @@ impl<C: AsRefContext> Dl<C> {
                 .unwrap_or_default()
                 .to_string();
             let mut opt = tokio::fs::OpenOptions::new();
-            opt.create(true).write(true).truncate(true);
+            opt.create(true).write(true);
+            let offset = match resume {
+                Some((len, _)) if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT => {
+                    info!("resume download {} from {}", part, len);
+                    opt.append(true);
+                    len
+                }
+                _ => {
+                    opt.truncate(true);
+                    // If-Range only accepts a strong etag or a date
+                    let validator = Some(etag.as_str())
+                        .filter(|e| !e.is_empty() && !e.starts_with("W/"))
+                        .or_else(|| {
+                            resp.headers()
+                                .get(header::LAST_MODIFIED)
+                                .and_then(|v| v.to_str().ok())
+                        });
+                    match validator {
+                        Some(v) => {
+                            let record = Record::new(OpKind::Resume, v, "");
+                            ctx.db.set_record(&part, "", &record).await?
+                        }
+                        None => ctx.db.del(&part, "").await?,
+                    }
+                    0
+                }
+            };
             let mut file = loop {
-                match opt.open(&path).await {
+                match opt.open(&part).await {
                     Ok(file) => break Ok(file),
                     Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
                         if let Some(parent) = Path::new(&path).parent() {
@@ -105,24 +373,234 @@ impl<C: AsRefContext> Dl<C> {
                     Err(e) => break Err(e),
                 }
             }?;
+            let progress = tracker.file(path, resp.content_length().map(|l| l + offset));
+            progress.add(offset);
+            let mut size = offset;
             while let Some(chunk) = resp.chunk().await? {
                 tokio::io::copy(&mut chunk.as_ref(), &mut file).await?;
+                size += chunk.len() as u64;
+                progress.add(chunk.len() as u64);
+                progress.throttle(chunk.len() as u64).await;
             }
-            debug!("downloaded to {}", path);
-            ctx.db.set(path, "", &self.now.to_string(), &etag).await?;
-        } else if resp.status().as_u16() == 304 {
+            drop(progress);
+            file.flush().await?;
+            drop(file);
+            let record = Record {
+                size: Some(size),
+                meta: Some(freshness.to_meta()),
+                ..Record::new(OpKind::Download, now.to_string(), etag)
+            };
+            Self::store(ctx, path, &url, expected.as_ref(), record).await?;
+        } else if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
             debug!("not modified in server, use cache {}", path);
-            ctx.db
-                .set(
-                    path,
-                    "",
-                    &self.now.to_string(),
-                    &self.etag.expect("etag should exists"),
-                )
-                .await?;
+            let Some(cached) = cached else {
+                bail!("unexpected 304 for {url} without a cached file")
+            };
+            let etag = resp
+                .headers()
+                .get(header::ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map_or(cached.latest, str::to_string);
+            let freshness = freshness.or(Freshness::from_meta(cached.meta.as_deref()));
+            let record = Record {
+                hash,
+                size: cached.size,
+                meta: Some(freshness.to_meta()),
+                ..Record::new(OpKind::Download, now.to_string(), etag)
+            };
+            ctx.db.set_record(path, "", &record).await?;
         } else {
+            if resp.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+                debug!("drop unusable partial download {}", part);
+                ctx.db.del(&part, "").await?;
+                tokio::fs::remove_file(&part).await?;
+            }
             bail!("failed to fetch url, status: {}", resp.status())
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        HttpClient, HttpConfig, MemoryDb, MultiDB,
+        http::stub::{Response, serve},
+        interactor::TermInteractor,
+    };
+
+    const ABC: &str = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    /// A context caching into `dir`, with a short retry backoff.
+    pub(super) fn context(dir: &std::path::Path) -> Context {
+        let mut db = MultiDB::default();
+        db.add_db(MemoryDb::new());
+        let mut ctx = Context::new(db, Some(dir.into()), TermInteractor::new().unwrap());
+        ctx.http = HttpClient::new(&HttpConfig {
+            backoff: Duration::from_millis(10),
+            ..Default::default()
+        })
+        .unwrap();
+        ctx
+    }
+
+    #[tokio::test]
+    async fn checksum() {
+        let flaky = std::sync::atomic::AtomicBool::new(true);
+        let base = serve(move |req| match req.path.as_str() {
+            "/f" if flaky.swap(false, std::sync::atomic::Ordering::SeqCst) => {
+                Response::new(502, "")
+            }
+            "/f" | "/g" | "/h" => Response::new(200, "abc"),
+            "/SUMS" => Response::new(200, format!("{0}  f\n{0}  h\n", &ABC[7..])),
+            _ => Response::new(404, ""),
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path());
+
+        for checksum in [ABC.to_string(), format!("sha256:{base}/SUMS")] {
+            let (path, dl) = Dl::new(
+                &ctx,
+                format!("{base}/f"),
+                None,
+                Some(checksum.parse().unwrap()),
+            )
+            .await
+            .unwrap();
+            dl.unwrap().execute(&path).await.unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "abc");
+            let record = ctx.db.get_record(&path, "").await.unwrap().unwrap();
+            assert_eq!(record.hash.as_deref(), Some(ABC));
+        }
+
+        // a file cached without a checksum isn't a hit until it was verified
+        let sums: Checksum = format!("sha256:{base}/SUMS").parse().unwrap();
+        let (path, dl) = Dl::new(&ctx, format!("{base}/h"), Some(u64::MAX), None)
+            .await
+            .unwrap();
+        dl.unwrap().execute(&path).await.unwrap();
+        for hit in [false, true] {
+            let (path, dl) = Dl::new(
+                &ctx,
+                format!("{base}/h"),
+                Some(u64::MAX),
+                Some(sums.clone()),
+            )
+            .await
+            .unwrap();
+            assert_eq!(dl.is_none(), hit);
+            if let Some(dl) = dl {
+                dl.execute(&path).await.unwrap();
+            }
+        }
+        let record = ctx.db.get_record(&path, "").await.unwrap().unwrap();
+        assert_eq!(record.hash.as_deref(), Some(ABC));
+
+        let pinned = Checksum::Digest(Digest::of(Algorithm::Sha256, b"abd"));
+        let (path, dl) = Dl::new(&ctx, format!("{base}/g"), None, Some(pinned))
+            .await
+            .unwrap();
+        let err = dl.unwrap().execute(&path).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DlError>(),
+            Some(DlError::ChecksumMismatch { .. })
+        ));
+        assert!(!std::path::Path::new(&path).exists());
+        assert!(!std::path::Path::new(&format!("{path}.dvpart")).exists());
+        assert!(ctx.db.get_record(&path, "").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn revalidate() {
+        const DATE: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let hits = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = hits.clone();
+        let base = serve(move |req| {
+            let conditional = (req.header("if-none-match"), req.header("if-modified-since"));
+            log.lock().unwrap().push(req.path.clone());
+            match (req.path.as_str(), conditional) {
+                ("/fresh", _) => Response::new(200, "a").header("Cache-Control", "max-age=600"),
+                ("/lm", (None, Some(DATE))) => Response::new(304, ""),
+                ("/lm", _) => Response::new(200, "b")
+                    .header("Last-Modified", DATE)
+                    .header("Cache-Control", "no-cache"),
+                ("/moved", _) => Response::new(302, "").header("Location", "/target"),
+                ("/target", (Some("\"t\""), _)) => Response::new(304, ""),
+                ("/target", _) => Response::new(200, "c").header("ETag", "\"t\""),
+                ("/nostore", (None, None)) => Response::new(200, "d")
+                    .header("ETag", "\"n\"")
+                    .header("Cache-Control", "no-store"),
+                _ => Response::new(400, ""),
+            }
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path());
+        let fetch = async |name: &str| {
+            let (path, dl) = Dl::new(&ctx, format!("{base}/{name}"), None, None)
+                .await
+                .unwrap();
+            if let Some(dl) = dl {
+                dl.execute(&path).await.unwrap();
+            }
+            path
+        };
+        for name in ["fresh", "lm", "moved", "nostore"] {
+            let path = fetch(name).await;
+            let content = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(fetch(name).await, path);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+        }
+        // max-age skips the second request, the others revalidate
+        let hits = hits.lock().unwrap().clone();
+        assert_eq!(
+            hits,
+            [
+                "/fresh", "/lm", "/lm", "/moved", "/target", "/moved", "/target", "/nostore",
+                "/nostore"
+            ]
+        );
+        let path = fetch("moved").await;
+        let record = ctx.db.get_record(&path, "").await.unwrap().unwrap();
+        assert_eq!(record.latest, "\"t\"");
+        let freshness = Freshness::from_meta(record.meta.as_deref());
+        assert_eq!(freshness.url, Some(format!("{base}/target")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = context(&dir.path().join("cache"));
+        ctx.add_user(
+            "this".to_string(),
+            User::local(dv_api::multi::Config::default()).await.unwrap(),
+        )
+        .await
+        .unwrap();
+        let src = dir.path().join("artifact");
+        let fetch = async |url: &str| {
+            let (path, dl) = Dl::new(&ctx, url, None, None).await.unwrap();
+            let copied = dl.is_some();
+            if let Some(dl) = dl {
+                dl.execute(&path).await.unwrap();
+            }
+            (std::fs::read_to_string(&path).unwrap(), copied)
+        };
+        let file = format!("file://{}", src.display());
+        let user = format!("user://this{}", src.display());
+        for url in [&file, &user] {
+            std::fs::write(&src, "v1").unwrap();
+            assert_eq!(fetch(url).await, ("v1".into(), true));
+            assert_eq!(fetch(url).await, ("v1".into(), false));
+            std::fs::write(&src, "v22").unwrap();
+            assert_eq!(fetch(url).await, ("v22".into(), true));
+        }
+        let missing = format!("user://nobody{}", src.display());
+        assert!(Dl::new(&ctx, &missing, None, None).await.is_err());
+    }
+}
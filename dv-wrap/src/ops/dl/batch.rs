@@ -0,0 +1,133 @@
+//! Downloading many urls at once.
+use futures::{StreamExt, stream};
+use tracing::debug;
+
+use super::{super::dev::*, Checksum, Dl};
+use crate::RateLimit;
+
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Downloads into the cache with bounded concurrency.
+pub struct DlBatch<'a> {
+    ctx: &'a Context,
+    concurrency: usize,
+    expire: Option<u64>,
+    rate_limit: Option<RateLimit>,
+}
+
+impl<'a> DlBatch<'a> {
+    pub fn new(ctx: &'a Context) -> Self {
+        Self {
+            ctx,
+            concurrency: DEFAULT_CONCURRENCY,
+            expire: None,
+            rate_limit: None,
+        }
+    }
+    /// Set the maximum number of downloads at the same time.
+    pub fn set_concurrency(&mut self, limit: usize) {
+        self.concurrency = limit.max(1);
+    }
+    /// Use cached files for `expire` seconds, see [`Dl::new`].
+    pub fn set_expire(&mut self, expire: Option<u64>) {
+        self.expire = expire;
+    }
+    /// Limit the bandwidth of each download.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.rate_limit = limit;
+    }
+
+    /// Download all `urls`, returning the cached paths in the same order.
+    ///
+    /// A url listed more than once with the same checksum is downloaded once and shares the
+    /// result. A failed download doesn't stop the others.
+    pub async fn execute<U: AsRef<str>>(
+        &self,
+        urls: &[(U, Option<Checksum>)],
+    ) -> Vec<Result<String>> {
+        let mut unique: Vec<(&str, &Option<Checksum>)> = Vec::new();
+        let slots = urls
+            .iter()
+            .map(|(url, checksum)| {
+                let key = (url.as_ref(), checksum);
+                match unique.iter().position(|k| *k == key) {
+                    Some(i) => {
+                        debug!("merge duplicate download of {}", key.0);
+                        i
+                    }
+                    None => {
+                        unique.push(key);
+                        unique.len() - 1
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        let results = stream::iter(unique)
+            .map(|(url, checksum)| self.download(url, checksum.clone()))
+            .buffered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        slots
+            .into_iter()
+            .map(|i| match &results[i] {
+                Ok(path) => Ok(path.clone()),
+                Err(e) => Err(anyhow::anyhow!("{e:#}")),
+            })
+            .collect()
+    }
+
+    async fn download(&self, url: &str, checksum: Option<Checksum>) -> Result<String> {
+        let (path, dl) = Dl::new(self.ctx, url, self.expire, checksum).await?;
+        if let Some(mut dl) = dl {
+            dl.set_rate_limit(self.rate_limit);
+            dl.execute(&path).await?;
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::{super::tests::context, *};
+    use crate::http::stub::{Response, serve};
+
+    #[tokio::test]
+    async fn batch() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let count = hits.clone();
+        let base = serve(move |req| {
+            count.fetch_add(1, Ordering::SeqCst);
+            match req.path.as_str() {
+                "/missing" => Response::new(404, ""),
+                path => Response::new(200, path[1..].to_string()),
+            }
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path());
+        let mut batch = DlBatch::new(&ctx);
+        batch.set_concurrency(2);
+        batch.set_expire(Some(3600));
+
+        let urls = ["a", "b", "a", "missing", "a"].map(|p| (format!("{base}/{p}"), None));
+        let results = batch.execute(&urls).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        let read = |r: &Result<String>| std::fs::read_to_string(r.as_ref().unwrap()).unwrap();
+        assert_eq!(read(&results[0]), "a");
+        assert_eq!(read(&results[1]), "b");
+        assert_eq!(results[0].as_ref().unwrap(), results[4].as_ref().unwrap());
+        assert!(results[3].is_err());
+
+        // batches sharing the cache wait for the lock and reuse the download
+        let urls = [(format!("{base}/c"), None)];
+        let (first, second) = tokio::join!(batch.execute(&urls), batch.execute(&urls));
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+        assert_eq!(read(&first[0]), "c");
+        assert_eq!(first[0].as_ref().unwrap(), second[0].as_ref().unwrap());
+    }
+}
@@ -0,0 +1,328 @@
+//! Management of the download cache.
+//!
+//! Downloads are the files directly in the cache dir named by [`file_name`] of their url. Their
+//! records, partial downloads and extractions are removed together with them, so [`Dl::new`]
+//! never finds a record without its file. Extractions count towards the size of their download.
+//!
+//! [`Dl::new`]: super::Dl::new
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use base64::Engine;
+use tracing::{debug, info};
+
+use super::super::dev::*;
+use crate::OpKind;
+
+const ENGINE: base64::engine::GeneralPurpose = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+/// The name of the cached file of `url`.
+pub fn file_name(url: &str) -> String {
+    ENGINE.encode(url)
+}
+
+/// A download in the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cached {
+    pub url: String,
+    pub path: PathBuf,
+    /// Bytes of the file and its extractions.
+    pub size: u64,
+    /// Unix time of the last download, revalidation or cache hit, the file mtime if unrecorded.
+    pub last_used: i64,
+}
+
+fn cache_dir(ctx: &Context) -> Result<&PathBuf> {
+    match &ctx.cache_dir {
+        Some(dir) => Ok(dir),
+        None => bail!("cache dir not set"),
+    }
+}
+
+/// The cached downloads, least recently used first.
+pub async fn list(ctx: &Context) -> Result<Vec<Cached>> {
+    let dir = cache_dir(ctx)?;
+    let mut cached = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(cached),
+        Err(e) => Err(e)?,
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let meta = entry.metadata().await?;
+        let name = entry.file_name();
+        // partial downloads and temporary files aren't valid base64
+        let url = name
+            .to_str()
+            .and_then(|n| ENGINE.decode(n).ok())
+            .and_then(|u| String::from_utf8(u).ok());
+        let Some(url) = url.filter(|u| meta.is_file() && u.contains("://")) else {
+            continue;
+        };
+        let path = entry.path();
+        let record = ctx.db.get_record(&path.to_string_lossy(), "").await?;
+        let mtime = meta
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let mut size = meta.len();
+        for dir in extractions(ctx, &path.to_string_lossy()).await? {
+            size += tokio::task::spawn_blocking(move || dir_size(Path::new(&dir))).await??;
+        }
+        cached.push(Cached {
+            url,
+            size,
+            last_used: record.and_then(|r| r.synced_at).unwrap_or(mtime),
+            path,
+        });
+    }
+    cached.sort_by(|a, b| (a.last_used, &a.url).cmp(&(b.last_used, &b.url)));
+    Ok(cached)
+}
+
+/// The directories the download at `path` was extracted into.
+async fn extractions(ctx: &Context, path: &str) -> Result<Vec<String>> {
+    let entries = ctx.db.list(Some(path), "").await?.into_iter();
+    Ok(entries
+        .filter(|e| e.record.kind == Some(OpKind::Extract) && !e.path.is_empty())
+        .map(|e| e.path)
+        .collect())
+}
+
+/// Bytes of the files below `dir`, nothing if it is gone.
+fn dir_size(dir: &Path) -> Result<u64> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => Err(e)?,
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += if meta.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            meta.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Wait for an exclusive lock on `<path>.dvlock` of the cached file at `path`, held until the
+/// returned file is closed.
+///
+/// Processes sharing the cache dir take it from the cache lookup until the download is stored,
+/// and to remove the download, so they wait for each other instead of writing the same partial
+/// download or removing it midway.
+pub(super) async fn lock(path: &str) -> Result<std::fs::File> {
+    let path = PathBuf::from(format!("{path}.dvlock"));
+    debug!("lock {}", path.display());
+    let file = tokio::task::spawn_blocking(move || {
+        loop {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            lock_file(&file)?;
+            // a removal deletes the lock file while holding it, so its waiters lock a stale file
+            if same_file(&file, &path)? {
+                return std::io::Result::Ok(file);
+            }
+        }
+    })
+    .await??;
+    Ok(file)
+}
+
+#[cfg(not(windows))]
+fn lock_file(file: &std::fs::File) -> std::io::Result<()> {
+    use rustix::fs::{FlockOperation, flock};
+    flock(file, FlockOperation::LockExclusive)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn lock_file(file: &std::fs::File) -> std::io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows::Win32::{
+        Foundation::HANDLE,
+        Storage::FileSystem::{LOCKFILE_EXCLUSIVE_LOCK, LockFileEx},
+        System::IO::OVERLAPPED,
+    };
+    let mut overlapped = OVERLAPPED::default();
+    unsafe {
+        LockFileEx(
+            HANDLE(file.as_raw_handle()),
+            LOCKFILE_EXCLUSIVE_LOCK,
+            None,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    }
+    .map_err(std::io::Error::other)
+}
+
+/// Whether `file` is still the one at `path`.
+#[cfg(not(windows))]
+fn same_file(file: &std::fs::File, path: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let at = match std::fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let meta = file.metadata()?;
+    Ok((meta.dev(), meta.ino()) == (at.dev(), at.ino()))
+}
+
+/// Whether `file` is still the one at `path`, always on Windows, where an open file can't be
+/// replaced.
+#[cfg(windows)]
+fn same_file(_file: &std::fs::File, _path: &Path) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+async fn remove_file(path: &str) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Remove the download of `url` with its partial download, extractions, lock and their records.
+async fn remove(ctx: &Context, url: &str, path: &str) -> Result<()> {
+    let lock = lock(path).await?;
+    info!("remove cached {url}");
+    let part = format!("{path}.dvpart");
+    remove_file(path).await?;
+    remove_file(&part).await?;
+    for dir in extractions(ctx, path).await? {
+        debug!("remove extraction {dir}");
+        if tokio::fs::try_exists(&dir).await? {
+            tokio::fs::remove_dir_all(&dir).await?;
+        }
+    }
+    // the records of the download and its extractions
+    ctx.db.del(path, "").await?;
+    ctx.db.del(&part, "").await?;
+    remove_file(&format!("{path}.dvlock")).await?;
+    drop(lock);
+    Ok(())
+}
+
+/// Remove the download of `url`, returning whether it was cached.
+pub async fn purge(ctx: &Context, url: &str) -> Result<bool> {
+    let path = cache_dir(ctx)?.join(file_name(url));
+    let path = path.to_string_lossy();
+    let cached = tokio::fs::try_exists(&*path).await?;
+    remove(ctx, url, &path).await?;
+    Ok(cached)
+}
+
+/// Remove the downloads unused for `max_age`, returning them.
+pub async fn purge_older(ctx: &Context, max_age: Duration) -> Result<Vec<Cached>> {
+    let deadline = crate::db::now().saturating_sub(max_age.as_secs() as i64);
+    let mut removed = Vec::new();
+    for cached in list(ctx).await? {
+        if cached.last_used < deadline {
+            remove(ctx, &cached.url, &cached.path.to_string_lossy()).await?;
+            removed.push(cached);
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove the least recently used downloads until the rest fit in `max_size` bytes, returning
+/// the removed ones.
+pub async fn evict(ctx: &Context, max_size: u64) -> Result<Vec<Cached>> {
+    let cached = list(ctx).await?;
+    let mut total = cached.iter().map(|c| c.size).sum::<u64>();
+    let mut removed = Vec::new();
+    for cached in cached {
+        if total <= max_size {
+            break;
+        }
+        remove(ctx, &cached.url, &cached.path.to_string_lossy()).await?;
+        total -= cached.size;
+        removed.push(cached);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::tests::context, *};
+    use crate::{
+        Record,
+        http::stub::{Response, serve},
+        ops::Dl,
+    };
+
+    #[tokio::test]
+    async fn evict() {
+        let base =
+            serve(|req| Response::new(200, vec![b'x'; req.path[1..].parse().unwrap()])).await;
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path());
+        let fetch = async |size: u64| {
+            let url = format!("{base}/{size}");
+            let (path, dl) = Dl::new(&ctx, &url, Some(3600), None).await.unwrap();
+            if let Some(dl) = dl {
+                dl.execute(&path).await.unwrap();
+            }
+            path
+        };
+        // used in the order 10, 30, 20
+        for (size, used) in [(10, 0), (20, 50), (30, 25)] {
+            let path = fetch(size).await;
+            let record = ctx.db.get_record(&path, "").await.unwrap().unwrap();
+            let record = Record {
+                synced_at: Some(now() - 100 + used),
+                ..record
+            };
+            ctx.db.set_record(&path, "", &record).await.unwrap();
+        }
+        let ten = dir.path().join(file_name(&format!("{base}/10")));
+        std::fs::write(format!("{}.dvpart", ten.display()), "").unwrap();
+        // an extraction of the first download
+        let extracted = dir.path().join("extract/x");
+        std::fs::create_dir_all(extracted.join("bin")).unwrap();
+        std::fs::write(extracted.join("bin/tool"), [b'x'; 40]).unwrap();
+        let record = Record::new(OpKind::Extract, "", "");
+        let (ten, extracted_str) = (ten.to_string_lossy(), extracted.to_string_lossy());
+        ctx.db
+            .set_record(&ten, &extracted_str, &record)
+            .await
+            .unwrap();
+        let sizes = |list: Vec<Cached>| list.iter().map(|c| c.size).collect::<Vec<_>>();
+        assert_eq!(sizes(list(&ctx).await.unwrap()), [50, 30, 20]);
+
+        assert_eq!(sizes(super::evict(&ctx, 55).await.unwrap()), [50]);
+        assert!(!extracted.exists());
+        assert!(ctx.db.list(Some(&ten), "").await.unwrap().is_empty());
+        // the two downloads with their locks and the extract dir
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 5);
+        assert_eq!(
+            sizes(purge_older(&ctx, Duration::from_secs(60)).await.unwrap()),
+            [30]
+        );
+
+        // a hit marks the download as used
+        let path = fetch(20).await;
+        assert!(list(&ctx).await.unwrap()[0].last_used >= now() - 1);
+        assert!(purge(&ctx, &format!("{base}/20")).await.unwrap());
+        assert!(!purge(&ctx, &format!("{base}/20")).await.unwrap());
+        assert!(ctx.db.get_record(&path, "").await.unwrap().is_none());
+        assert!(!std::path::Path::new(&format!("{path}.dvlock")).exists());
+        assert!(list(&ctx).await.unwrap().is_empty());
+    }
+
+    fn now() -> i64 {
+        crate::db::now()
+    }
+}
@@ -0,0 +1,298 @@
+//! Expected digests of downloads.
+//!
+//! A digest is written as `<algorithm>:<hex>`, the form kept in [`Record::hash`](crate::Record).
+//! Instead of the hex, a checksum file url in the `sha256sum` or BSD format may be given, the
+//! line of the downloaded file's name is used.
+use std::{fmt, path::Path, str::FromStr};
+
+use sha2::{Digest as _, Sha256, Sha512};
+use tokio::io::AsyncReadExt;
+use tracing::debug;
+
+use super::{super::dev::*, DlError};
+use crate::HttpClient;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Algorithm {
+    /// Length of the hex digest.
+    fn hex_len(self) -> usize {
+        match self {
+            Algorithm::Sha256 | Algorithm::Blake3 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+    fn finalize(self) -> String {
+        let bytes = match self {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        };
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    pub algorithm: Algorithm,
+    /// Lowercase hex.
+    pub hex: String,
+}
+
+impl Digest {
+    pub fn new(algorithm: Algorithm, hex: &str) -> Result<Self> {
+        let hex = hex.trim().to_ascii_lowercase();
+        if hex.len() != algorithm.hex_len() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("invalid {algorithm} digest {hex}")
+        }
+        Ok(Self { algorithm, hex })
+    }
+    pub fn of(algorithm: Algorithm, data: &[u8]) -> Self {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(data);
+        Self {
+            algorithm,
+            hex: hasher.finalize(),
+        }
+    }
+    /// Digest of the local file at `path`.
+    pub async fn of_file(algorithm: Algorithm, path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut hasher = Hasher::new(algorithm);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(Self {
+            algorithm,
+            hex: hasher.finalize(),
+        })
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex)
+    }
+}
+
+fn split(s: &str) -> Result<(Algorithm, &str)> {
+    let Some((algorithm, rest)) = s.split_once(':') else {
+        bail!("checksum must be <algorithm>:<digest>, got {s}")
+    };
+    let Ok(algorithm) = algorithm.parse() else {
+        bail!("unknown checksum algorithm {algorithm}")
+    };
+    Ok((algorithm, rest))
+}
+
+impl FromStr for Digest {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, hex) = split(s)?;
+        Digest::new(algorithm, hex)
+    }
+}
+
+/// What a download is verified against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Digest(Digest),
+    /// A checksum file listing the digest of the download.
+    File {
+        algorithm: Algorithm,
+        url: reqwest::Url,
+    },
+}
+
+/// `sha256:<hex>` or `sha256:https://example.com/SHA256SUMS`.
+impl FromStr for Checksum {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, rest) = split(s)?;
+        if rest.starts_with("http://") || rest.starts_with("https://") {
+            Ok(Checksum::File {
+                algorithm,
+                url: rest.parse()?,
+            })
+        } else {
+            Ok(Checksum::Digest(Digest::new(algorithm, rest)?))
+        }
+    }
+}
+
+impl Checksum {
+    /// The expected digest of `url`, fetching the checksum file if needed.
+    pub(super) async fn resolve(&self, client: &HttpClient, url: &reqwest::Url) -> Result<Digest> {
+        let (algorithm, sums) = match self {
+            Checksum::Digest(d) => return Ok(d.clone()),
+            Checksum::File { algorithm, url } => (*algorithm, url),
+        };
+        debug!("fetch checksum file {}", sums);
+        let resp = client.get(sums.clone()).await?;
+        if !resp.status().is_success() {
+            bail!(
+                "failed to fetch checksum file {sums}, status: {}",
+                resp.status()
+            )
+        }
+        let text = resp.text().await?;
+        let name = url
+            .path_segments()
+            .and_then(|mut s| s.next_back())
+            .unwrap_or_default();
+        find(&text, name, algorithm)
+    }
+}
+
+/// The digest of `name` in a checksum file, or its only digest if it has no file names.
+fn find(text: &str, name: &str, algorithm: Algorithm) -> Result<Digest> {
+    let lines = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'));
+    let mut bare = Vec::new();
+    for line in lines {
+        // BSD: `SHA256 (name) = hex`
+        if let Some((head, hex)) = line.split_once(") = ")
+            && let Some((_, file)) = head.split_once(" (")
+        {
+            if file == name {
+                return Digest::new(algorithm, hex);
+            }
+            continue;
+        }
+        // GNU: `hex  name` or `hex *name`
+        match line.split_once(char::is_whitespace) {
+            Some((hex, file)) => {
+                let file = file.trim_start().trim_start_matches('*');
+                if file.trim_start_matches("./") == name {
+                    return Digest::new(algorithm, hex);
+                }
+            }
+            None => bare.push(line),
+        }
+    }
+    match bare.as_slice() {
+        [hex] => Digest::new(algorithm, hex),
+        _ => bail!("no {algorithm} checksum for {name}"),
+    }
+}
+
+/// Check the downloaded `path` against `expected`, removing it on a mismatch.
+pub(super) async fn verify(path: &str, expected: &Digest, url: &str) -> Result<Digest> {
+    let actual = Digest::of_file(expected.algorithm, path).await?;
+    if actual != *expected {
+        tokio::fs::remove_file(path).await?;
+        return Err(DlError::ChecksumMismatch {
+            url: url.to_string(),
+            expected: expected.clone(),
+            actual,
+        }
+        .into());
+    }
+    Ok(actual)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn digests() {
+        assert_eq!(Digest::of(Algorithm::Sha256, b"abc").hex, SHA256);
+        assert_eq!(
+            Digest::of(Algorithm::Blake3, b"abc").hex,
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert!(
+            Digest::of(Algorithm::Sha512, b"abc")
+                .hex
+                .starts_with("ddaf35a193617aba")
+        );
+        let d: Digest = format!("SHA256:{}", SHA256.to_uppercase()).parse().unwrap();
+        assert_eq!(d.to_string(), format!("sha256:{SHA256}"));
+        assert!("sha256:abc".parse::<Digest>().is_err());
+        assert!("md5:abc".parse::<Checksum>().is_err());
+        assert!(matches!(
+            "sha512:https://example.com/SHA512SUMS".parse(),
+            Ok(Checksum::File {
+                algorithm: Algorithm::Sha512,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn checksum_file() {
+        let other = "0".repeat(64);
+        let gnu = format!("{other}  a.tar.gz\n{SHA256} *./b.tar.gz\n");
+        let bsd = format!("SHA256 (a.tar.gz) = {other}\nSHA256 (b.tar.gz) = {SHA256}\n");
+        for text in [gnu, bsd, format!("{SHA256}\n")] {
+            let d = find(&text, "b.tar.gz", Algorithm::Sha256).unwrap();
+            assert_eq!(d.hex, SHA256);
+        }
+        assert!(
+            find(
+                &format!("{other}  a.tar.gz\n"),
+                "b.tar.gz",
+                Algorithm::Sha256
+            )
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.dvpart").to_string_lossy().to_string();
+        std::fs::write(&path, "abc").unwrap();
+        let expected = Digest::new(Algorithm::Sha256, SHA256).unwrap();
+        assert_eq!(verify(&path, &expected, "u").await.unwrap(), expected);
+
+        std::fs::write(&path, "abd").unwrap();
+        let err = verify(&path, &expected, "u").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DlError>(),
+            Some(DlError::ChecksumMismatch { .. })
+        ));
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
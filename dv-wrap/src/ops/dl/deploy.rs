@@ -0,0 +1,146 @@
+//! Installing cached downloads onto users.
+use std::path::Path;
+
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info};
+
+use super::{
+    super::dev::*,
+    checksum::{Algorithm, Digest},
+};
+use crate::{OpKind, Record, progress::Tracker};
+
+/// Install the local `src`, a cached download or an extracted member, to `dst` of user `uid`.
+///
+/// The file is uploaded next to `dst` and renamed over it, created with `mode` (less the umask
+/// of the device). The upload is skipped if the record of `dst` has the same digest and mode and
+/// the file still has the recorded size and mtime. Returns whether the file was uploaded.
+pub async fn deploy<C: AsRefContext>(
+    ctx: C,
+    src: impl AsRef<Path>,
+    uid: &str,
+    dst: &str,
+    mode: u32,
+) -> Result<bool> {
+    let ctx = ctx.as_ref();
+    let src = src.as_ref();
+    let user = ctx.get_user(uid)?;
+    let digest = Digest::of_file(Algorithm::Sha256, src).await?.to_string();
+    let size = tokio::fs::metadata(src).await?.len();
+    let dst_path = U8Path::new(dst);
+    if let Some(record) = ctx.db.get_record(uid, dst).await?
+        && record.hash.as_deref() == Some(&digest)
+        && record.mode == Some(mode)
+        && let (_, Some(attr)) = user.file_attributes(dst_path).await?
+        && attr.size == Some(size)
+        && attr.mtime.map(i64::from) == record.mtime
+    {
+        debug!("{uid}:{dst} is current");
+        return Ok(false);
+    }
+    info!("deploy {} to {uid}:{dst}", src.display());
+    let tmp = U8PathBuf::from(format!("{dst}.dvtmp"));
+    // the mode only applies to a new file
+    if user.exist(&tmp).await? {
+        user.rm(&tmp).await?;
+    }
+    let tracker = Tracker::new(format!("deploy {uid}:{dst}"), 1);
+    let upload = async {
+        let file = tracker.file(dst, Some(size));
+        let from = tokio::fs::File::open(src).await?;
+        let attr = FileAttributes {
+            permissions: Some(mode),
+            ..Default::default()
+        };
+        let mut to = user
+            .open_with_attr(
+                &tmp,
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                attr,
+            )
+            .await?;
+        tokio::io::copy(&mut file.reader(from), &mut to).await?;
+        to.shutdown().await?;
+        drop(to);
+        user.rename(&tmp, dst_path).await
+    };
+    tracker.run(&*ctx.interactor, upload).await?;
+    let Some(mtime) = user.get_mtime(dst_path).await? else {
+        bail!("{uid}:{dst} not found after deploy")
+    };
+    let record = Record {
+        hash: Some(digest.clone()),
+        size: Some(size),
+        mode: Some(mode),
+        mtime: Some(mtime),
+        ..Record::new(OpKind::Deploy, digest, mtime.to_string())
+    };
+    ctx.db.set_record(uid, dst, &record).await?;
+    Ok(true)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use dv_api::multi::Config;
+
+    use super::{super::tests::context, *};
+
+    #[tokio::test]
+    async fn deploy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = context(&dir.path().join("cache"));
+        let mut cfg = Config::default();
+        cfg.set("mount", dir.path().to_string_lossy());
+        ctx.add_user("this".to_string(), User::local(cfg).await.unwrap())
+            .await
+            .unwrap();
+        let src = dir.path().join("tool");
+        std::fs::write(&src, "v1").unwrap();
+        let installed = dir.path().join("bin/tool");
+        let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+
+        assert!(
+            super::deploy(&ctx, &src, "this", "bin/tool", 0o750)
+                .await
+                .unwrap()
+        );
+        assert_eq!(std::fs::read_to_string(&installed).unwrap(), "v1");
+        assert_eq!(mode(&installed), 0o750);
+        assert!(
+            !super::deploy(&ctx, &src, "this", "bin/tool", 0o750)
+                .await
+                .unwrap()
+        );
+
+        // a changed mode, source or destination uploads again
+        assert!(
+            super::deploy(&ctx, &src, "this", "bin/tool", 0o700)
+                .await
+                .unwrap()
+        );
+        assert_eq!(mode(&installed), 0o700);
+        std::fs::write(&src, "v2").unwrap();
+        assert!(
+            super::deploy(&ctx, &src, "this", "bin/tool", 0o700)
+                .await
+                .unwrap()
+        );
+        std::fs::write(&installed, "local edit").unwrap();
+        assert!(
+            super::deploy(&ctx, &src, "this", "bin/tool", 0o700)
+                .await
+                .unwrap()
+        );
+        assert_eq!(std::fs::read_to_string(&installed).unwrap(), "v2");
+        let record = ctx
+            .db
+            .get_record("this", "bin/tool")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.kind, Some(OpKind::Deploy));
+        assert_eq!(record.size, Some(2));
+    }
+}
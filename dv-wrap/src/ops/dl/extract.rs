@@ -0,0 +1,385 @@
+//! Extraction of downloaded archives into the cache.
+//!
+//! The archive is unpacked by the system `tar`, `unzip` or `gzip` into a staging directory,
+//! then the selected members are moved into place with their leading components stripped.
+//! The tool a format needs has to be on the `PATH`, its absence is reported by name.
+//! The result lives in `<cache dir>/extract/<key>` where the key hashes the url and the digest
+//! of the archive, so a new release gets a directory of its own.
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use sha2::{Digest as _, Sha256};
+use tracing::{debug, info};
+
+use super::{
+    super::dev::*,
+    checksum::{Algorithm, Digest},
+};
+use crate::{OpKind, Record};
+
+/// The meta of an [`OpKind::Extract`] record.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct ExtractMeta {
+    /// The url of the archive.
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ArchiveFormat {
+    TarGz,
+    TarXz,
+    TarBz2,
+    Tar,
+    Zip,
+    /// A single gzip compressed file, named after the archive without `.gz`.
+    Gz,
+}
+
+impl ArchiveFormat {
+    /// Detect the format from the file name of an archive.
+    pub fn detect(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        const SUFFIXES: [(&str, ArchiveFormat); 9] = [
+            (".tar.gz", ArchiveFormat::TarGz),
+            (".tgz", ArchiveFormat::TarGz),
+            (".tar.xz", ArchiveFormat::TarXz),
+            (".txz", ArchiveFormat::TarXz),
+            (".tar.bz2", ArchiveFormat::TarBz2),
+            (".tbz2", ArchiveFormat::TarBz2),
+            (".tar", ArchiveFormat::Tar),
+            (".zip", ArchiveFormat::Zip),
+            (".gz", ArchiveFormat::Gz),
+        ];
+        SUFFIXES
+            .iter()
+            .find(|(suffix, _)| name.ends_with(suffix))
+            .map(|(_, format)| *format)
+    }
+}
+
+/// How a downloaded archive is extracted.
+#[derive(Debug, Clone, Default)]
+pub struct Extract {
+    /// Detected from the url if not set.
+    pub format: Option<ArchiveFormat>,
+    /// Leading path components dropped from each member, like `tar --strip-components`.
+    pub strip_components: usize,
+    /// Globs of the members to keep, matched after stripping; all members if empty.
+    pub members: Vec<String>,
+    /// Globs of the members made executable, matched after stripping.
+    pub executable: Vec<String>,
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
+impl Extract {
+    /// The options as recorded in the cache, a change extracts the archive again.
+    fn fingerprint(&self, format: ArchiveFormat) -> String {
+        format!(
+            "{format} strip={} members={} executable={}",
+            self.strip_components,
+            self.members.join(","),
+            self.executable.join(",")
+        )
+    }
+    /// Extract the downloaded `archive` of `url`, returning the directory of its members.
+    ///
+    /// Nothing is done if the directory was already extracted from the same archive with the
+    /// same options. The extraction is recorded under the path of the archive and the directory,
+    /// so it is found and removed with the download.
+    pub async fn extract<C: AsRefContext>(
+        &self,
+        ctx: C,
+        url: &str,
+        archive: impl AsRef<Path>,
+    ) -> Result<PathBuf> {
+        let ctx = ctx.as_ref();
+        let archive = archive.as_ref();
+        let Some(cdir) = &ctx.cache_dir else {
+            bail!("cache dir not set, cannot extract {}", url)
+        };
+        let name = url
+            .parse::<reqwest::Url>()?
+            .path_segments()
+            .and_then(|mut s| s.next_back())
+            .unwrap_or_default()
+            .to_string();
+        let Some(format) = self.format.or_else(|| ArchiveFormat::detect(&name)) else {
+            bail!("unknown archive format of {url}")
+        };
+        let digest = Digest::of_file(Algorithm::Sha256, archive)
+            .await?
+            .to_string();
+        let key = Sha256::digest(format!("{url}\n{digest}").as_bytes());
+        let key = key[..16]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        let dest = cdir.join("extract").join(key);
+        let uid = archive.to_string_lossy().to_string();
+        let dir = dest.to_string_lossy().to_string();
+        let fingerprint = self.fingerprint(format);
+        if tokio::fs::metadata(&dest).await.is_ok_and(|m| m.is_dir())
+            && let Some(record) = ctx.db.get_record(&uid, &dir).await?
+            && record.version == digest
+            && record.latest == fingerprint
+        {
+            debug!("extraction of {} is current", url);
+            return Ok(dest);
+        }
+        info!("extract {} to {}", url, dest.display());
+        let staging = PathBuf::from(format!("{dir}.dvtmp"));
+        if tokio::fs::metadata(&staging).await.is_ok() {
+            tokio::fs::remove_dir_all(&staging).await?;
+        }
+        let raw = staging.join("raw");
+        tokio::fs::create_dir_all(&raw).await?;
+        unpack(format, archive, &raw, name.trim_end_matches(".gz")).await?;
+        let out = staging.join("out");
+        let (members, executable) = (glob_set(&self.members)?, glob_set(&self.executable)?);
+        let strip = self.strip_components;
+        let moved = {
+            let (raw, out) = (raw.clone(), out.clone());
+            tokio::task::spawn_blocking(move || {
+                select(&raw, &raw, &out, strip, &members, &executable)
+            })
+            .await??
+        };
+        if moved == 0 {
+            tokio::fs::remove_dir_all(&staging).await?;
+            bail!("no members of {url} left to extract");
+        }
+        if tokio::fs::metadata(&dest).await.is_ok() {
+            tokio::fs::remove_dir_all(&dest).await?;
+        }
+        tokio::fs::rename(&out, &dest).await?;
+        tokio::fs::remove_dir_all(&staging).await?;
+        let record = Record {
+            meta: Some(serde_json::to_string(&ExtractMeta { url: url.into() })?),
+            ..Record::new(OpKind::Extract, digest, fingerprint)
+        };
+        ctx.db.set_record(&uid, &dir, &record).await?;
+        Ok(dest)
+    }
+}
+
+/// Unpack all of `archive` into `dir`.
+async fn unpack(format: ArchiveFormat, archive: &Path, dir: &Path, name: &str) -> Result<()> {
+    let tar = |flag: &str| {
+        let mut cmd = tokio::process::Command::new("tar");
+        cmd.arg(format!("-x{flag}f"))
+            .arg(archive)
+            .arg("-C")
+            .arg(dir);
+        cmd
+    };
+    let mut cmd = match format {
+        ArchiveFormat::TarGz => tar("z"),
+        ArchiveFormat::TarXz => tar("J"),
+        ArchiveFormat::TarBz2 => tar("j"),
+        ArchiveFormat::Tar => tar(""),
+        ArchiveFormat::Zip => {
+            let mut cmd = tokio::process::Command::new("unzip");
+            cmd.arg("-qo").arg(archive).arg("-d").arg(dir);
+            cmd
+        }
+        ArchiveFormat::Gz => {
+            let name = if name.is_empty() { "file" } else { name };
+            let file = std::fs::File::create(dir.join(name))?;
+            let mut cmd = tokio::process::Command::new("gzip");
+            cmd.arg("-dc").arg(archive).stdout(Stdio::from(file));
+            cmd
+        }
+    };
+    let program = cmd.as_std().get_program().to_string_lossy().to_string();
+    // `output` would pipe stdout, which is the target file for gzip
+    let child = cmd.stdin(Stdio::null()).stderr(Stdio::piped()).spawn();
+    let child = child.map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => {
+            anyhow::anyhow!("{program} is needed to unpack {format} archives but wasn't found")
+        }
+        _ => anyhow::anyhow!("failed to run {program}: {e}"),
+    })?;
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        bail!(
+            "{program} failed to unpack {}: {}",
+            archive.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Move the selected files below `dir` into `out`, returning how many were moved.
+fn select(
+    root: &Path,
+    dir: &Path,
+    out: &Path,
+    strip: usize,
+    members: &GlobSet,
+    executable: &GlobSet,
+) -> Result<usize> {
+    let mut moved = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            moved += select(root, &path, out, strip, members, executable)?;
+            continue;
+        }
+        let rel = path.strip_prefix(root)?;
+        if rel.components().count() <= strip {
+            continue;
+        }
+        let rel = rel.components().skip(strip).collect::<PathBuf>();
+        if !members.is_empty() && !members.is_match(&rel) {
+            continue;
+        }
+        let target = out.join(&rel);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&path, &target)?;
+        #[cfg(not(unix))]
+        let _ = executable;
+        #[cfg(unix)]
+        if executable.is_match(&rel) {
+            use std::os::unix::fs::PermissionsExt;
+            let mut perm = std::fs::metadata(&target)?.permissions();
+            perm.set_mode(perm.mode() | 0o111);
+            std::fs::set_permissions(&target, perm)?;
+        }
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, process::Command};
+
+    use super::{super::tests::context, *};
+
+    #[test]
+    fn detect() {
+        assert_eq!(
+            ArchiveFormat::detect("a-1.0.TGZ"),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect("a.tar.xz"),
+            Some(ArchiveFormat::TarXz)
+        );
+        assert_eq!(ArchiveFormat::detect("a.gz"), Some(ArchiveFormat::Gz));
+        assert_eq!(ArchiveFormat::detect("a.exe"), None);
+    }
+
+    fn run(cmd: &mut Command) {
+        assert!(cmd.status().unwrap().success());
+    }
+
+    /// A `pkg-1.0` directory with `bin/tool` and `README` below `dir/src`, returning `dir/src`.
+    fn package(dir: &Path) -> PathBuf {
+        let src = dir.join("src");
+        std::fs::create_dir_all(src.join("pkg-1.0/bin")).unwrap();
+        std::fs::write(src.join("pkg-1.0/bin/tool"), "#!/bin/sh\n").unwrap();
+        std::fs::write(src.join("pkg-1.0/README"), "readme").unwrap();
+        src
+    }
+
+    /// Extract the executable `bin/tool` of the package `archive` of `url`, twice.
+    async fn extract_tool(ctx: &Context, url: &str, archive: &Path) {
+        let opts = Extract {
+            strip_components: 1,
+            members: vec!["bin/*".into()],
+            executable: vec!["bin/tool".into()],
+            ..Default::default()
+        };
+        let out = opts.extract(ctx, url, archive).await.unwrap();
+        let tool = out.join("bin/tool");
+        assert_eq!(std::fs::read_to_string(&tool).unwrap(), "#!/bin/sh\n");
+        assert_eq!(
+            std::fs::metadata(&tool).unwrap().permissions().mode() & 0o111,
+            0o111
+        );
+        assert!(!out.join("README").exists());
+
+        // a current extraction is kept as is
+        std::fs::write(&tool, "changed").unwrap();
+        assert_eq!(opts.extract(ctx, url, archive).await.unwrap(), out);
+        assert_eq!(std::fs::read_to_string(&tool).unwrap(), "changed");
+    }
+
+    #[tokio::test]
+    async fn extract() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = package(dir.path());
+        let tgz = dir.path().join("pkg.tar.gz");
+        run(Command::new("tar")
+            .arg("-czf")
+            .arg(&tgz)
+            .arg("-C")
+            .arg(&src)
+            .arg("pkg-1.0"));
+        let gz = dir.path().join("tool.gz");
+        run(Command::new("sh")
+            .arg("-c")
+            .arg(format!("echo hi | gzip -c > {}", gz.display())));
+
+        let ctx = context(&dir.path().join("cache"));
+        extract_tool(&ctx, "http://h/pkg.tar.gz", &tgz).await;
+
+        // other options extract again
+        let all = Extract {
+            strip_components: 1,
+            ..Default::default()
+        };
+        let out = all
+            .extract(&ctx, "http://h/pkg.tar.gz", &tgz)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out.join("README")).unwrap(),
+            "readme"
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("bin/tool")).unwrap(),
+            "#!/bin/sh\n"
+        );
+
+        let out = Extract {
+            executable: vec!["*".into()],
+            ..Default::default()
+        }
+        .extract(&ctx, "http://h/tool.gz", &gz)
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("tool")).unwrap(), "hi\n");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the zip and unzip tools"]
+    async fn extract_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = package(dir.path());
+        let zip = dir.path().join("pkg.zip");
+        run(Command::new("zip")
+            .arg("-qr")
+            .arg(&zip)
+            .arg("pkg-1.0")
+            .current_dir(&src));
+        let ctx = context(&dir.path().join("cache"));
+        extract_tool(&ctx, "http://h/pkg.zip", &zip).await;
+    }
+}
@@ -0,0 +1,106 @@
+//! HTTP caching hints of downloads, kept as JSON in the meta of their [`Record`](crate::Record).
+use reqwest::header::{self, HeaderMap};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(super) struct Freshness {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// `Cache-Control: max-age` in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    /// `Cache-Control: no-cache`, the file is revalidated before every use.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub no_cache: bool,
+    /// `Cache-Control: no-store`, the file is fetched again without validators.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub no_store: bool,
+    /// The url the response came from after redirects.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl Freshness {
+    pub fn from_response(resp: &reqwest::Response) -> Self {
+        let mut this = Self::from_headers(resp.headers());
+        this.url = Some(resp.url().to_string());
+        this
+    }
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let mut this = Self {
+            last_modified: headers
+                .get(header::LAST_MODIFIED)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            ..Default::default()
+        };
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for directive in directives {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("max-age", age)) => this.max_age = age.trim_matches('"').parse().ok(),
+                None if directive == "no-cache" => this.no_cache = true,
+                None if directive == "no-store" => this.no_store = true,
+                _ => {}
+            }
+        }
+        this
+    }
+    /// Fill what a 304 response left out from the hints of the cached response.
+    pub fn or(self, cached: Self) -> Self {
+        Self {
+            last_modified: self.last_modified.or(cached.last_modified),
+            url: self.url.or(cached.url),
+            ..self
+        }
+    }
+    /// Whether a response fetched at `fetched` can be used at `now` without revalidation.
+    pub fn fresh(&self, fetched: u64, now: u64) -> bool {
+        !self.no_cache
+            && !self.no_store
+            && self
+                .max_age
+                .is_some_and(|age| now.saturating_sub(fetched) < age)
+    }
+    pub fn to_meta(&self) -> String {
+        serde_json::to_string(self).expect("hints serialize to JSON")
+    }
+    /// The hints in `meta`, none if it isn't from [`to_meta`](Self::to_meta).
+    pub fn from_meta(meta: Option<&str>) -> Self {
+        meta.and_then(|m| serde_json::from_str(m).ok())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_control() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::CACHE_CONTROL, "public, max-age=60".parse().unwrap());
+        headers.append(header::CACHE_CONTROL, "No-Cache".parse().unwrap());
+        headers.insert(
+            header::LAST_MODIFIED,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        let f = Freshness::from_headers(&headers);
+        assert_eq!(f.max_age, Some(60));
+        assert!(f.no_cache && !f.no_store);
+        assert!(!f.fresh(100, 110));
+        assert_eq!(Freshness::from_meta(Some(&f.to_meta())), f);
+
+        let f = Freshness {
+            max_age: Some(60),
+            ..Default::default()
+        };
+        assert!(f.fresh(100, 159) && !f.fresh(100, 160));
+        assert_eq!(Freshness::from_meta(Some("{}")), Freshness::default());
+    }
+}
@@ -0,0 +1,333 @@
+//! Resolving the latest release asset of a GitHub or GitLab project.
+use std::collections::HashMap;
+
+use dv_api::process::Script;
+use os2::Os;
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use super::{super::dev::*, Dl};
+use crate::{OpKind, Record, utils::var_replace};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forge {
+    GitHub,
+    GitLab,
+}
+
+/// The latest release of a project, with the asset picked by a glob.
+///
+/// `${os}` in the pattern is replaced by `linux`, `macos`, `windows` or `unix` and `${arch}` by
+/// the machine of the target user, e.g. `x86_64` or `aarch64`, so one pattern like
+/// `*${os}-${arch}*.tar.gz` fits every device.
+#[derive(Debug, Clone)]
+pub struct Release {
+    pub forge: Forge,
+    /// The root of the API, e.g. `https://api.github.com` or `https://gitlab.com/api/v4`.
+    pub api: String,
+    /// `owner/repo`, or the full path of a GitLab project.
+    pub repo: String,
+    pub pattern: String,
+}
+
+/// The response of the GitHub latest release API.
+#[derive(Deserialize)]
+struct GitHubRelease {
+    tag_name: String,
+    #[serde(default)]
+    assets: Vec<GitHubAsset>,
+}
+
+#[derive(Deserialize)]
+struct GitHubAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// The response of the GitLab latest release API.
+#[derive(Deserialize)]
+struct GitLabRelease {
+    tag_name: String,
+    #[serde(default)]
+    assets: GitLabAssets,
+}
+
+#[derive(Deserialize, Default)]
+struct GitLabAssets {
+    #[serde(default)]
+    links: Vec<GitLabLink>,
+}
+
+#[derive(Deserialize)]
+struct GitLabLink {
+    name: String,
+    direct_asset_url: Option<String>,
+    url: Option<String>,
+}
+
+/// The meta of an [`OpKind::Release`] record.
+#[derive(Serialize)]
+struct ReleaseMeta {
+    /// The name of the asset.
+    name: String,
+}
+
+/// An asset of a release.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReleaseAsset {
+    pub tag: String,
+    pub name: String,
+    pub url: String,
+}
+
+impl Release {
+    pub fn github(repo: impl Into<String>, pattern: impl Into<String>) -> Self {
+        Self {
+            forge: Forge::GitHub,
+            api: "https://api.github.com".into(),
+            repo: repo.into(),
+            pattern: pattern.into(),
+        }
+    }
+    pub fn gitlab(repo: impl Into<String>, pattern: impl Into<String>) -> Self {
+        Self {
+            forge: Forge::GitLab,
+            api: "https://gitlab.com/api/v4".into(),
+            repo: repo.into(),
+            pattern: pattern.into(),
+        }
+    }
+    /// Use a self-hosted instance.
+    pub fn set_api(&mut self, api: impl Into<String>) {
+        self.api = api.into();
+    }
+
+    /// The tag of the latest release with the names and urls of its assets.
+    async fn latest(&self, ctx: &Context) -> Result<(String, Vec<(String, String)>)> {
+        let api = self.api.trim_end_matches('/');
+        let url = match self.forge {
+            Forge::GitHub => format!("{api}/repos/{}/releases/latest", self.repo),
+            Forge::GitLab => format!(
+                "{api}/projects/{}/releases/permalink/latest",
+                self.repo.replace('/', "%2F")
+            ),
+        };
+        debug!("query latest release {url}");
+        let mut req = reqwest::Request::new(reqwest::Method::GET, url.parse()?);
+        if self.forge == Forge::GitHub {
+            req.headers_mut().insert(
+                header::ACCEPT,
+                header::HeaderValue::from_static("application/vnd.github+json"),
+            );
+        }
+        let resp = ctx.http.execute(req).await?;
+        if !resp.status().is_success() {
+            bail!("failed to query {url}, status: {}", resp.status())
+        }
+        let text = resp.text().await?;
+        Ok(match self.forge {
+            Forge::GitHub => {
+                let release: GitHubRelease = serde_json::from_str(&text)?;
+                let assets = release.assets.into_iter();
+                let assets = assets.map(|a| (a.name, a.browser_download_url));
+                (release.tag_name, assets.collect())
+            }
+            Forge::GitLab => {
+                let release: GitLabRelease = serde_json::from_str(&text)?;
+                let links = release.assets.links.into_iter();
+                // older GitLab releases only have the plain link
+                let assets = links.filter_map(|l| Some((l.name, l.direct_asset_url.or(l.url)?)));
+                (release.tag_name, assets.collect())
+            }
+        })
+    }
+
+    /// The asset of the latest release for the user `uid`.
+    pub async fn resolve(&self, ctx: &Context, uid: &str) -> Result<ReleaseAsset> {
+        let user = ctx.get_user(uid)?;
+        let os = match user.os() {
+            Os::Linux(_) => "linux",
+            Os::MacOs => "macos",
+            Os::Windows => "windows",
+            _ => "unix",
+        };
+        let vars = HashMap::from([
+            ("os".to_string(), os.to_string()),
+            ("arch".to_string(), arch(user).await?),
+        ]);
+        let Some(pattern) = var_replace(&self.pattern, &vars) else {
+            bail!("unknown variable in {}", self.pattern)
+        };
+        let glob = globset::Glob::new(&pattern)?.compile_matcher();
+        let (tag, assets) = self.latest(ctx).await?;
+        let Some((name, url)) = assets.iter().find(|(name, _)| glob.is_match(name)) else {
+            let names = assets.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+            bail!(
+                "no asset of {} {tag} matches {pattern}, found: {}",
+                self.repo,
+                names.join(", ")
+            )
+        };
+        Ok(ReleaseAsset {
+            tag,
+            name: name.clone(),
+            url: url.clone(),
+        })
+    }
+
+    /// Download the asset of the latest release for the user `uid` into the cache.
+    ///
+    /// The resolved tag is recorded for `uid`, and the asset is only downloaded again once the
+    /// tag changes. Returns the cached path, the asset and whether it changed.
+    pub async fn fetch(&self, ctx: &Context, uid: &str) -> Result<(String, ReleaseAsset, bool)> {
+        let asset = self.resolve(ctx, uid).await?;
+        let key = format!(
+            "release:{}/{}:{}",
+            self.api.trim_end_matches('/'),
+            self.repo,
+            self.pattern
+        );
+        let record = ctx.db.get_record(uid, &key).await?;
+        let unchanged = record
+            .as_ref()
+            .is_some_and(|r| r.version == asset.tag && r.latest == asset.url);
+        // the asset of a tag doesn't change, so the cached file is kept for good
+        let (path, dl) = Dl::new(ctx, &asset.url, Some(u64::MAX), None).await?;
+        if let Some(dl) = dl {
+            dl.execute(&path).await?;
+        }
+        if unchanged {
+            debug!("{} is still at {}", self.repo, asset.tag);
+            return Ok((path, asset, false));
+        }
+        info!("{} released {} for {uid}", self.repo, asset.tag);
+        let meta = ReleaseMeta {
+            name: asset.name.clone(),
+        };
+        let record = Record {
+            meta: Some(serde_json::to_string(&meta)?),
+            ..Record::new(OpKind::Release, &asset.tag, &asset.url)
+        };
+        ctx.db.set_record(uid, &key, &record).await?;
+        Ok((path, asset, true))
+    }
+}
+
+/// The machine of `user` as named by `uname -m`, with the common aliases unified.
+async fn arch(user: &User) -> Result<String> {
+    let arch = if let Some(arch) = user.vars.get("arch") {
+        arch.clone()
+    } else if user.os().is_windows() {
+        match user.vars.get("PROCESSOR_ARCHITECTURE") {
+            Some(arch) => arch.clone(),
+            None => bail!("unknown architecture of a windows user"),
+        }
+    } else {
+        let output = user.exec(Script::sh("uname -m")).await?;
+        if output.code != 0 {
+            bail!("uname -m failed with {}", output.code)
+        }
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+    Ok(match arch.to_ascii_lowercase().as_str() {
+        "amd64" | "x64" => "x86_64".into(),
+        "arm64" => "aarch64".into(),
+        "x86" | "i386" | "i586" => "i686".into(),
+        other => other.into(),
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dv_api::multi::Config;
+
+    use super::{super::tests::context, *};
+    use crate::http::stub::{Response, serve};
+
+    #[tokio::test]
+    async fn latest() {
+        // the tag and the base url of the assets
+        let state = Arc::new(Mutex::new((String::from("v1"), String::new())));
+        let shared = state.clone();
+        let base = serve(move |req| {
+            let (tag, base) = shared.lock().unwrap().clone();
+            let arch = std::env::consts::ARCH;
+            let asset = |name: String| (format!("{base}/dl/{tag}/{name}"), name);
+            let assets = [
+                asset(format!("tool-linux-{arch}.tar.gz")),
+                asset("tool-linux-riscv64.tar.gz".into()),
+                asset("tool-windows-x86_64.zip".into()),
+            ];
+            match req.path.as_str() {
+                "/repos/o/tool/releases/latest" => {
+                    let assets = assets
+                        .iter()
+                        .map(|(url, name)| {
+                            format!(r#"{{"name":"{name}","browser_download_url":"{url}"}}"#)
+                        })
+                        .collect::<Vec<_>>();
+                    let body = format!(r#"{{"tag_name":"{tag}","assets":[{}]}}"#, assets.join(","));
+                    Response::new(200, body)
+                }
+                "/projects/g%2Ftool/releases/permalink/latest" => {
+                    let links = assets
+                        .iter()
+                        .map(|(url, name)| {
+                            format!(r#"{{"name":"{name}","direct_asset_url":"{url}"}}"#)
+                        })
+                        .collect::<Vec<_>>();
+                    let body = format!(
+                        r#"{{"tag_name":"{tag}","assets":{{"links":[{}]}}}}"#,
+                        links.join(",")
+                    );
+                    Response::new(200, body)
+                }
+                path if path.starts_with("/dl/") => Response::new(200, path.to_string()),
+                _ => Response::new(404, ""),
+            }
+        })
+        .await;
+        state.lock().unwrap().1 = base.clone();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = context(&dir.path().join("cache"));
+        let mut cfg = Config::default();
+        cfg.set("os", "linux");
+        ctx.add_user("this".to_string(), User::local(cfg).await.unwrap())
+            .await
+            .unwrap();
+        let mut github = Release::github("o/tool", "tool-${os}-${arch}.tar.gz");
+        github.set_api(&base);
+        let expected = format!("tool-linux-{}.tar.gz", std::env::consts::ARCH);
+
+        let (path, asset, updated) = github.fetch(&ctx, "this").await.unwrap();
+        assert!(updated);
+        assert_eq!(
+            (asset.tag.as_str(), asset.name.as_str()),
+            ("v1", &*expected)
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("/dl/v1/{expected}")
+        );
+        assert!(!github.fetch(&ctx, "this").await.unwrap().2);
+
+        state.lock().unwrap().0 = "v2".into();
+        let (path, asset, updated) = github.fetch(&ctx, "this").await.unwrap();
+        assert!(updated && asset.tag == "v2");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("/dl/v2/{expected}")
+        );
+
+        let mut gitlab = Release::gitlab("g/tool", "*-${os}-${arch}.tar.gz");
+        gitlab.set_api(&base);
+        assert_eq!(gitlab.resolve(&ctx, "this").await.unwrap(), asset);
+        let mut missing = Release::github("o/tool", "*-macos-*");
+        missing.set_api(&base);
+        assert!(missing.resolve(&ctx, "this").await.is_err());
+    }
+}
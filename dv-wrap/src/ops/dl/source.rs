@@ -0,0 +1,109 @@
+//! Downloads copied from a file instead of fetched over the network.
+use std::path::PathBuf;
+
+use tokio::io::AsyncRead;
+
+use super::super::dev::*;
+
+/// A `file://` path on the controller or a `user://<uid>/<path>` file of a user.
+///
+/// The path of a user is absolute, `user://srv/~/out.tar` names one below its home.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Source {
+    File(PathBuf),
+    User { uid: String, path: U8PathBuf },
+}
+
+impl Source {
+    /// The source of `url`, none if it is fetched over HTTP.
+    pub fn parse(url: &reqwest::Url) -> Result<Option<Self>> {
+        match url.scheme() {
+            "http" | "https" => Ok(None),
+            "file" => match url.to_file_path() {
+                Ok(path) => Ok(Some(Source::File(path))),
+                Err(_) => bail!("invalid file url: {url}"),
+            },
+            "user" => {
+                let Some(uid) = url.host_str().filter(|h| !h.is_empty()) else {
+                    bail!("no user in {url}")
+                };
+                let decoded = percent_encoding::percent_decode_str(url.path()).decode_utf8()?;
+                let path = decoded.strip_prefix('/').filter(|p| p.starts_with('~'));
+                Ok(Some(Source::User {
+                    uid: uid.to_string(),
+                    path: path.unwrap_or(&decoded).into(),
+                }))
+            }
+            scheme => bail!("unsupported url scheme {scheme}: {url}"),
+        }
+    }
+    /// The size and a validator of the current content, `<size>:<mtime>`.
+    pub async fn stat(&self, ctx: &Context) -> Result<(u64, String)> {
+        let (size, mtime) = match self {
+            Source::File(path) => {
+                let meta = tokio::fs::metadata(path).await?;
+                if !meta.is_file() {
+                    bail!("{} is not a file", path.display())
+                }
+                let mtime = meta
+                    .modified()?
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_secs();
+                (meta.len(), mtime)
+            }
+            Source::User { uid, path } => {
+                let (_, attr) = ctx.get_user(uid)?.file_attributes(path).await?;
+                let Some(attr) = attr.filter(|a| !a.is_dir()) else {
+                    bail!("{uid}:{path} is not a file")
+                };
+                match (attr.size, attr.mtime) {
+                    (Some(size), Some(mtime)) => (size, mtime as u64),
+                    _ => bail!("{uid}:{path} has no size or mtime"),
+                }
+            }
+        };
+        Ok((size, format!("{size}:{mtime}")))
+    }
+    pub async fn open(&self, ctx: &Context) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        Ok(match self {
+            Source::File(path) => Box::new(tokio::fs::File::open(path).await?),
+            Source::User { uid, path } => {
+                Box::new(ctx.get_user(uid)?.open(path, OpenFlags::READ).await?)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let parse = |url: &str| Source::parse(&url.parse().unwrap());
+        assert_eq!(parse("https://example.com/f").unwrap(), None);
+        assert_eq!(
+            parse("user://srv/opt/f").unwrap(),
+            Some(Source::User {
+                uid: "srv".into(),
+                path: "/opt/f".into()
+            })
+        );
+        assert_eq!(
+            parse("user://srv/~/f").unwrap(),
+            Some(Source::User {
+                uid: "srv".into(),
+                path: "~/f".into()
+            })
+        );
+        assert_eq!(
+            parse("user://srv/tmp/a b/é").unwrap(),
+            Some(Source::User {
+                uid: "srv".into(),
+                path: "/tmp/a b/é".into()
+            })
+        );
+        assert!(parse("user:///f").is_err());
+        assert!(parse("ftp://example.com/f").is_err());
+    }
+}
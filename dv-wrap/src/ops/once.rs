@@ -1,4 +1,5 @@
 use super::dev::*;
+use crate::{OpKind, Record};
 
 pub struct Once<'b, T: AsRefContext> {
     pub ctx: T,
@@ -15,7 +16,8 @@ impl<'b, T: AsRefContext> Once<'b, T> {
     }
     pub async fn execute(&self) -> Result<()> {
         let ctx = self.ctx.as_ref();
-        ctx.db.set(self.id, self.key, "", "").await?;
+        let record = Record::new(OpKind::Once, "", "");
+        ctx.db.set_record(self.id, self.key, &record).await?;
         Ok(())
     }
 }
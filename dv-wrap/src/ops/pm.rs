@@ -57,6 +57,24 @@ impl Pm {
             update  = ["update"], []
             upgrade = ["upgrade"], []
         }
+        dnf5 {
+            name = "dnf5",
+            install = ["install"], ["-y"]
+            update  = ["makecache"], []
+            upgrade = ["upgrade"], ["-y"]
+        }
+        dnf {
+            name = "dnf",
+            install = ["install"], ["-y"]
+            update  = ["makecache"], []
+            upgrade = ["upgrade"], ["-y"]
+        }
+        yum {
+            name = "yum",
+            install = ["install"], ["-y"]
+            update  = ["makecache"], []
+            upgrade = ["update"], ["-y"]
+        }
         apt {
             name = "apt",
             install = ["install"], ["-y"]
@@ -110,6 +128,11 @@ impl Pm {
                 LinuxOs::Debian => platform::debian::detect(u).await,
                 LinuxOs::Alpine => platform::alpine::detect(u).await,
                 LinuxOs::Ubuntu => platform::ubuntu::detect(u).await,
+                LinuxOs::Fedora
+                | LinuxOs::Rhel
+                | LinuxOs::CentOs
+                | LinuxOs::Rocky
+                | LinuxOs::Alma => platform::fedora::detect(u).await,
                 _ => bail!("Unknown LinuxOs {:?}", os),
             },
             Os::Windows => platform::windows::detect(u).await,
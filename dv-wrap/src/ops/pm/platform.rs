@@ -7,5 +7,6 @@ mod dev {
 pub mod alpine;
 pub mod arch;
 pub mod debian;
+pub mod fedora;
 pub mod ubuntu;
 pub mod windows;
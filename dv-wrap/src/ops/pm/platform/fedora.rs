@@ -0,0 +1,33 @@
+use dv_api::process::{PtyProcessConsumer, Script};
+use tracing::debug;
+
+use super::dev::*;
+
+/// Fedora and the RHEL family, dnf5 on Fedora 41 and later, dnf on RHEL 8 and later, yum before.
+pub async fn detect(u: &User) -> Result<Pm> {
+    debug!("try to detect fedora package manager");
+    let ec = u
+        .exec(Script::sh(
+            r#"if command -v dnf5 >/dev/null 2>&1; then
+    exit 1
+elif command -v dnf >/dev/null 2>&1; then
+    exit 2
+else
+    exit 0
+fi
+"#,
+        ))
+        .wait()
+        .await?;
+
+    if ec == 1 {
+        debug!("detected dnf5 as package manager");
+        Ok(Pm::dnf5())
+    } else if ec == 2 {
+        debug!("detected dnf as package manager");
+        Ok(Pm::dnf())
+    } else {
+        debug!("detected yum as package manager");
+        Ok(Pm::yum())
+    }
+}
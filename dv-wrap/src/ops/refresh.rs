@@ -1,7 +1,97 @@
 use super::dev::*;
+use super::sync::SyncMeta;
+use crate::{CacheEntry, OpKind};
 
 pub async fn refresh(ctx: &Context, id: impl AsRef<str>, key: impl AsRef<str>) -> Result<()> {
     let (id, key) = (id.as_ref(), key.as_ref());
     ctx.db.del(id, key).await?;
     Ok(())
 }
+
+/// Whether `path` is missing on the user `uid`, `None` if the user is unknown.
+async fn gone(ctx: &Context, uid: &str, path: &str) -> Result<Option<bool>> {
+    match ctx.users.get(uid) {
+        Some(user) => Ok(Some(!user.exist(path).await?)),
+        None => Ok(None),
+    }
+}
+
+async fn stale(ctx: &Context, entry: &CacheEntry) -> Result<bool> {
+    let record = &entry.record;
+    match record.kind {
+        // downloads are recorded under the path of the local file
+        Some(OpKind::Download | OpKind::Resume) if entry.path.is_empty() => {
+            Ok(!tokio::fs::try_exists(&entry.uid).await?)
+        }
+        // extractions under the path of the archive and the directory
+        Some(OpKind::Extract) => Ok(!tokio::fs::try_exists(&entry.path).await?),
+        Some(OpKind::Resume | OpKind::Deploy) => {
+            Ok(gone(ctx, &entry.uid, &entry.path).await? == Some(true))
+        }
+        Some(OpKind::Sync) => {
+            let meta = record.meta.as_deref();
+            let Some(meta) = meta.and_then(|m| serde_json::from_str::<SyncMeta>(m).ok()) else {
+                return Ok(false);
+            };
+            Ok(gone(ctx, &entry.uid, &entry.path).await? == Some(true)
+                && gone(ctx, &meta.src_uid, &meta.src).await? == Some(true))
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Remove the cache records of files that are gone, returning them.
+///
+/// A sync record goes once both its destination and source are missing, download and resume
+/// records once their file is, extraction records once their directory is. Records of unknown
+/// users or without a source stay, use [`MultiDB::prune_older`](crate::MultiDB::prune_older) to
+/// drop them by age.
+pub async fn prune(ctx: &Context) -> Result<Vec<CacheEntry>> {
+    let mut removed = Vec::new();
+    for entry in ctx.db.list(None, "").await? {
+        if stale(ctx, &entry).await? {
+            ctx.db.del(&entry.uid, &entry.path).await?;
+            removed.push(entry);
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{TempDir, prelude::*};
+    use dv_api::multi::Config;
+
+    use super::*;
+    use crate::{
+        MultiDB, Sqlite, TermInteractor,
+        ops::{SyncContext, SyncOpt},
+    };
+
+    #[tokio::test]
+    async fn prune() {
+        let mut db = MultiDB::default();
+        db.add_db(Sqlite::memory().unwrap());
+        let dir = TempDir::new().unwrap();
+        let mut cfg = Config::default();
+        cfg.set("mount", dir.to_string_lossy());
+        let mut ctx = Context::new(db, None, TermInteractor::new().unwrap());
+        ctx.add_user("this".to_string(), User::local(cfg).await.unwrap())
+            .await
+            .unwrap();
+        for name in ["f0", "f1"] {
+            dir.child("src").child(name).write_str(name).unwrap();
+        }
+        let sync = SyncContext::new(&ctx, "this", "this", &[SyncOpt::UPLOAD]);
+        let entries = sync.scan("src", "dst").await.unwrap();
+        assert!(sync.execute(&entries).await.unwrap());
+        // f0 is gone on both sides, f1 only on the destination
+        for path in ["src/f0", "dst/f0", "dst/f1"] {
+            std::fs::remove_file(dir.child(path)).unwrap();
+        }
+        let removed = super::prune(&ctx).await.unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].path, dir.child("dst/f0").to_str().unwrap());
+        assert_eq!(ctx.db.list(Some("this"), "").await.unwrap().len(), 1);
+    }
+}
@@ -1,23 +1,90 @@
 use super::dev::*;
 use anyhow::Result;
 use dv_api::fs::{Metadata, U8Path, U8PathBuf};
+use futures::{StreamExt, stream};
 use std::fmt::Write;
-use tracing::{debug, info};
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tracing::{debug, info, warn};
 
-use crate::{Context, MultiDB, interactor::DynInteractor};
+use crate::{
+    Context, MultiDB, OpKind, RateLimit, Record,
+    interactor::DynInteractor,
+    progress::{FileGuard, Tracker},
+};
 
-pub async fn try_copy(src: &User, src_path: &U8Path, dst: &User, dst_path: &U8Path) -> Result<()> {
-    let mut src = src.open(src_path, OpenFlags::READ).await?;
-    let mut dst = dst
-        .open(
+mod bulk;
+pub use bulk::Compression;
+mod delta;
+mod direct;
+mod fanout;
+pub use fanout::{FanOut, FanOutPlan, FanOutResult};
+mod policy;
+use policy::Policies;
+pub use policy::Policy;
+mod resume;
+#[cfg(all(test, unix))]
+mod sshd;
+mod watch;
+pub use watch::{DEFAULT_DEBOUNCE, DEFAULT_POLL_INTERVAL};
+
+/// Default number of entries [`SyncContext::execute`] transfers at the same time.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Read buffer size of [`try_copy`], large enough to keep several SFTP write requests in flight.
+const COPY_BUF_SIZE: usize = 255 * 1024;
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Copy `src_path` to `dst_path`, counting the transferred bytes towards `file` if given.
+pub async fn try_copy(
+    src: &User,
+    src_path: &U8Path,
+    dst: &User,
+    dst_path: &U8Path,
+    file: Option<&FileGuard<'_>>,
+) -> Result<()> {
+    // both opens are in flight at once instead of two sequential round-trips
+    let (from, mut dst) = tokio::try_join!(
+        src.open(src_path, OpenFlags::READ),
+        dst.open_from(
             dst_path,
             OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
-        )
-        .await?;
-    tokio::io::copy(&mut src, &mut dst).await?;
+            FileAttributes::default(),
+            src,
+        ),
+    )?;
+    let src: Box<dyn AsyncRead + Unpin + Send + '_> = match file {
+        Some(file) => Box::new(file.reader(from)),
+        None => Box::new(from),
+    };
+    let mut src = tokio::io::BufReader::with_capacity(COPY_BUF_SIZE, src);
+    tokio::io::copy_buf(&mut src, &mut dst).await?;
+    dst.shutdown().await?;
     Ok(())
 }
 
+async fn mtime(user: &User, path: &U8Path, known: Option<i64>) -> Result<i64> {
+    match known {
+        Some(t) => Ok(t),
+        None => match user.get_mtime(path).await? {
+            Some(t) => Ok(t),
+            None => bail!("{path} not found after copy"),
+        },
+    }
+}
+
+/// The source and destination mtimes of the last sync of a path.
+///
+/// Records from before the typed fields keep them in `version` and `latest`.
+fn synced_mtimes(record: &Record) -> Option<(i64, i64)> {
+    match (record.src_mtime, record.mtime) {
+        (Some(src), Some(dst)) => Some((src, dst)),
+        _ => Some((record.version.parse().ok()?, record.latest.parse().ok()?)),
+    }
+}
+
 bitflags::bitflags! {
     #[derive(Default,Debug, Clone, Copy, PartialEq, Eq, Hash)]
     pub struct Opt: u8 {
@@ -36,29 +103,80 @@ struct ScanContext<'a> {
     opts: &'a [Opt],
     suid: &'a str,
     duid: &'a str,
+    policies: &'a Policies,
+    /// Suffixes for files kept by [`Policy::KeepBoth`].
+    src_host: &'a str,
+    dst_host: &'a str,
+    /// Collects undecided entries instead of asking the interactor.
+    deferred: Option<&'a std::sync::Mutex<Vec<Entry>>>,
+}
+
+/// The meta of an [`OpKind::Sync`] record, the source lets a prune tell whether both sides are
+/// gone.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct SyncMeta {
+    pub src_uid: String,
+    pub src: String,
 }
 
-#[derive(Default)]
+#[derive(Debug, Default)]
 pub struct Entry {
     pub src: U8PathBuf,
     pub dst: U8PathBuf,
     pub src_attr: Option<i64>,
     pub dst_attr: Option<i64>,
+    /// Sizes from the scan, so the copy doesn't stat the file again.
+    pub src_size: Option<u64>,
+    pub dst_size: Option<u64>,
+    /// Directories are only created or removed, their content has entries of its own.
+    pub is_dir: bool,
     pub opt: Opt,
+    /// The policy that chose `opt`, `None` if it came from the preset opts or the interactor.
+    pub policy: Option<Policy>,
+    /// Where the overwritten side is moved before the copy, set by [`Policy::KeepBoth`].
+    pub backup: Option<U8PathBuf>,
 }
 
 impl<'a> ScanContext<'a> {
-    async fn select(&self, sp: &U8Path, dp: &U8Path, opt: Opt) -> Result<Opt> {
-        if opt.is_empty() {
-            return Ok(Opt::empty());
+    /// Choose the opt of `entry` out of the offered `entry.opt` by the preset opts, then the
+    /// policies, and ask the interactor last unless undecided entries are deferred.
+    async fn decide(&self, mut entry: Entry) -> Result<Option<Entry>> {
+        let offered = entry.opt;
+        if offered.is_empty() {
+            return Ok(None);
         }
         if let Some(o) = self
             .opts
             .iter()
-            .find(|&&o| o.is_empty() || ((o & opt) == o))
+            .find(|&&o| o.is_empty() || ((o & offered) == o))
         {
-            return Ok(*o);
+            entry.opt = *o;
+        } else if let Some(policy) = self.policies.get(&entry.src, &entry.dst) {
+            if !policy.apply(&mut entry, self.src_host, self.dst_host) {
+                bail!(
+                    "unresolved change {}:{} -> {}:{} under policy {policy}",
+                    self.suid,
+                    entry.src,
+                    self.duid,
+                    entry.dst
+                )
+            }
+            debug!(
+                "{}:{} -> {}:{} resolved to {:?} by {policy}",
+                self.suid, entry.src, self.duid, entry.dst, entry.opt
+            );
+        } else if let Some(deferred) = self.deferred {
+            deferred
+                .lock()
+                .expect("deferred entries poisoned")
+                .push(entry);
+            return Ok(None);
+        } else {
+            entry.opt = self.ask(&entry.src, &entry.dst, offered).await?;
         }
+        Ok((!entry.opt.is_empty()).then_some(entry))
+    }
+    async fn ask(&self, sp: &U8Path, dp: &U8Path, opt: Opt) -> Result<Opt> {
         let mut hint = String::new();
         let mut opts = Vec::new();
         write!(&mut hint, "{}:{sp} -> {}:{dp}", self.suid, self.duid).unwrap();
@@ -95,21 +213,16 @@ impl<'a> ScanContext<'a> {
         dst: impl Into<U8PathBuf>,
         sa: dv_api::fs::FileAttributes,
     ) -> Result<Option<Entry>> {
-        let src = src.into();
-        let dst = dst.into();
-        let opt = self
-            .select(&src, &dst, Opt::UPLOAD | Opt::DELETESRC)
-            .await?;
-        if opt.is_empty() {
-            return Ok(None);
-        }
-        Ok(Some(Entry {
-            src,
-            dst,
+        self.decide(Entry {
+            src: src.into(),
+            dst: dst.into(),
             src_attr: sa.mtime.map(|t| t as i64),
-            dst_attr: None,
-            opt,
-        }))
+            src_size: sa.size,
+            is_dir: sa.is_dir(),
+            opt: Opt::UPLOAD | Opt::DELETESRC,
+            ..Default::default()
+        })
+        .await
     }
     async fn select_dst(
         &self,
@@ -117,21 +230,16 @@ impl<'a> ScanContext<'a> {
         dst: impl Into<U8PathBuf>,
         da: dv_api::fs::FileAttributes,
     ) -> Result<Option<Entry>> {
-        let src = src.into();
-        let dst = dst.into();
-        let opt = self
-            .select(&src, &dst, Opt::DOWNLOAD | Opt::DELETEDST)
-            .await?;
-        if opt.is_empty() {
-            return Ok(None);
-        }
-        Ok(Some(Entry {
-            src,
-            dst,
-            src_attr: None,
+        self.decide(Entry {
+            src: src.into(),
+            dst: dst.into(),
             dst_attr: da.mtime.map(|t| t as i64),
-            opt,
-        }))
+            dst_size: da.size,
+            is_dir: da.is_dir(),
+            opt: Opt::DOWNLOAD | Opt::DELETEDST,
+            ..Default::default()
+        })
+        .await
     }
     async fn select_both(
         &self,
@@ -143,7 +251,8 @@ impl<'a> ScanContext<'a> {
         let src = src.into();
         let dst = dst.into();
         let mut flag = Opt::empty();
-        let db = self.db.get_as::<i64>(self.duid, dst.as_str()).await?;
+        let record = self.db.get_record(self.duid, dst.as_str()).await?;
+        let db = record.as_ref().and_then(synced_mtimes);
         debug!(db = ?db, "{} : {} = {}, {} : {} = {}",self.suid, src.as_str(), sa.mtime.unwrap_or_default(),self.duid, dst.as_str(), da.mtime.unwrap_or_default());
         if sa
             .mtime
@@ -157,17 +266,17 @@ impl<'a> ScanContext<'a> {
         {
             flag |= Opt::UPDATE;
         }
-        let opt = self.select(&src, &dst, flag).await?;
-        if opt.is_empty() {
-            return Ok(None);
-        }
-        Ok(Some(Entry {
+        self.decide(Entry {
             src,
             dst,
             src_attr: sa.mtime.map(|t| t as i64),
             dst_attr: da.mtime.map(|t| t as i64),
-            opt,
-        }))
+            src_size: sa.size,
+            dst_size: da.size,
+            opt: flag,
+            ..Default::default()
+        })
+        .await
     }
 
     async fn check_copy_dir2(
@@ -184,8 +293,9 @@ impl<'a> ScanContext<'a> {
             self.duid,
             dp.as_str()
         );
-        src_files.sort_by(|m1, m2| m1.path.as_str().cmp(m2.path.as_str()));
-        dst_files.sort_by(|m1, m2| m1.path.as_str().cmp(m2.path.as_str()));
+        // compare by components, the same way as the stripped paths below
+        src_files.sort_by(|m1, m2| m1.path.cmp(&m2.path));
+        dst_files.sort_by(|m1, m2| m1.path.cmp(&m2.path));
         let mut si = src_files.into_iter().peekable();
         let mut di = dst_files.into_iter().peekable();
         let mut entries = Vec::new();
@@ -197,10 +307,17 @@ impl<'a> ScanContext<'a> {
                     if ss == ds {
                         let sm = si.next().unwrap();
                         let dm = di.next().unwrap();
-                        entries.extend(
-                            self.select_both(&sm.path, &dm.path, sm.attr, dm.attr)
-                                .await?,
-                        );
+                        match (sm.attr.is_dir(), dm.attr.is_dir()) {
+                            (true, true) => {}
+                            (false, false) => entries.extend(
+                                self.select_both(&sm.path, &dm.path, sm.attr, dm.attr)
+                                    .await?,
+                            ),
+                            _ => warn!(
+                                "skip mismatched types: {}:{} and {}:{}",
+                                self.suid, sm.path, self.duid, dm.path
+                            ),
+                        }
                     } else if ss < ds {
                         let dp = dp.join(ss);
                         let sm = si.next().unwrap();
@@ -231,11 +348,44 @@ impl<'a> ScanContext<'a> {
         Ok(entries)
     }
 }
+/// The name of the device of `user`, falling back to its uid.
+fn host<'b>(user: &'b User, uid: &'b str) -> &'b str {
+    user.vars.get("hid").map_or(uid, String::as_str)
+}
+
+/// The source side of a scan, walked once and shared by every destination of a [`FanOut`].
+struct Source {
+    path: U8PathBuf,
+    attr: Option<FileAttributes>,
+    files: Vec<Metadata>,
+}
+
+impl Source {
+    async fn walk(user: &User, path: &str) -> Result<Self> {
+        let (path, attr) = user.file_attributes(path.into()).await?;
+        let files = match &attr {
+            Some(attr) if attr.is_dir() => user.glob(&path).await?,
+            _ => Vec::new(),
+        };
+        Ok(Self { path, attr, files })
+    }
+}
+
 pub struct SyncContext<'a> {
     ctx: &'a Context,
     opts: &'a [Opt],
     suid: &'a str,
     duid: &'a str,
+    concurrency: usize,
+    delta: Option<u64>,
+    debounce: std::time::Duration,
+    poll_interval: std::time::Duration,
+    policies: Policies,
+    direct: bool,
+    bulk: Option<Compression>,
+    rate_limit: Option<RateLimit>,
+    /// Receiving uids the other side couldn't reach directly.
+    unreachable: std::sync::Mutex<std::collections::HashSet<String>>,
 }
 
 impl<'a> SyncContext<'a> {
@@ -245,19 +395,95 @@ impl<'a> SyncContext<'a> {
             suid,
             duid,
             opts,
+            concurrency: DEFAULT_CONCURRENCY,
+            delta: None,
+            debounce: DEFAULT_DEBOUNCE,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            policies: Policies::default(),
+            direct: false,
+            bulk: None,
+            rate_limit: None,
+            unreachable: Default::default(),
         }
     }
 
+    /// Set the maximum number of entries transferred at the same time.
+    pub fn set_concurrency(&mut self, limit: usize) {
+        self.concurrency = limit.max(1);
+    }
+
+    /// Update existing files of at least `min_size` bytes with an rsync-like delta transfer.
+    ///
+    /// The destination must be able to run `python3`, otherwise the file is copied in full.
+    pub fn set_delta(&mut self, min_size: Option<u64>) {
+        self.delta = min_size;
+    }
+
+    /// Let the source host of a transfer between two SSH users push the file to the destination
+    /// itself instead of relaying it through this machine.
+    ///
+    /// Falls back to relaying when the hosts can't reach each other.
+    pub fn set_direct(&mut self, direct: bool) {
+        self.direct = direct;
+    }
+
+    /// Send new and overwritten files to the destination as one tar stream, unpacked by `tar` on
+    /// the destination.
+    ///
+    /// Meant for initial deploys of many small files; bulk transfers skip the direct, delta and
+    /// resumable copies. If the stream fails, its files are copied one by one instead.
+    pub fn set_bulk(&mut self, compression: Option<Compression>) {
+        self.bulk = compression;
+    }
+
+    /// Limit the bandwidth of one execution, shared by all its concurrent transfers.
+    ///
+    /// It applies on top of the limits of the users; limited transfers are never direct.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.rate_limit = limit;
+    }
+
+    /// Resolve changes the preset opts don't cover with `policy` instead of asking.
+    pub fn set_policy(&mut self, policy: Option<Policy>) {
+        self.policies.default = policy;
+    }
+
+    /// Resolve changes of paths matching the glob `pattern` with `policy`.
+    ///
+    /// Patterns are matched against the full source and destination paths, in the order they
+    /// were added, and take precedence over [`set_policy`](Self::set_policy).
+    pub fn add_policy(&mut self, pattern: &str, policy: Policy) -> Result<()> {
+        self.policies.add(pattern, policy)
+    }
+
+    /// Set how long [`watch`](Self::watch) waits for a burst of changes to settle.
+    pub fn set_debounce(&mut self, debounce: std::time::Duration) {
+        self.debounce = debounce;
+    }
+
+    /// Set how often [`watch`](Self::watch) rescans a side that can't be watched.
+    pub fn set_poll_interval(&mut self, interval: std::time::Duration) {
+        self.poll_interval = interval;
+    }
+
     pub async fn scan(
         &self,
         src_path: impl AsRef<str>,
         dst_path: impl AsRef<str>,
+    ) -> Result<Vec<Entry>> {
+        let source = Source::walk(self.ctx.get_user(self.suid)?, src_path.as_ref()).await?;
+        self.scan_from(&source, dst_path.as_ref(), None).await
+    }
+    /// Scan against an already walked source, pushing undecided entries to `deferred` if given.
+    async fn scan_from(
+        &self,
+        source: &Source,
+        dst_path: &str,
+        deferred: Option<&std::sync::Mutex<Vec<Entry>>>,
     ) -> Result<Vec<Entry>> {
         let src = self.ctx.get_user(self.suid)?;
         let dst = self.ctx.get_user(self.duid)?;
-        let src_path = src_path.as_ref();
-        let dst_path: &str = dst_path.as_ref();
-        let (src_path, src_attr) = src.file_attributes(src_path.into()).await?;
+        let (src_path, src_attr) = (source.path.clone(), source.attr.clone());
         let (dst_path, dst_attr) = dst.file_attributes(dst_path.into()).await?;
         info!(
             "sync {}:{} -> {}:{}",
@@ -269,23 +495,37 @@ impl<'a> SyncContext<'a> {
             opts: self.opts,
             suid: self.suid,
             duid: self.duid,
+            policies: &self.policies,
+            src_host: host(src, self.suid),
+            dst_host: host(dst, self.duid),
+            deferred,
         };
         match (src_attr, dst_attr) {
             (Some(src_attr), Some(dst_attr)) if src_attr.is_dir() && dst_attr.is_dir() => {
-                let src_files = src.glob(&src_path).await?;
+                let src_files = source.files.clone();
                 let dst_files = dst.glob(&dst_path).await?;
                 ctx.check_copy_dir2(src_path, src_files, dst_path, dst_files)
                     .await
             }
             (Some(src_attr), None) if src_attr.is_dir() => {
-                let src_files = src.glob(&src_path).await?;
-                ctx.check_copy_dir2(src_path, src_files, dst_path, Vec::new())
-                    .await
+                let src_files = source.files.clone();
+                let mut entries =
+                    Vec::from_iter(ctx.select_src(&src_path, &dst_path, src_attr).await?);
+                entries.extend(
+                    ctx.check_copy_dir2(src_path, src_files, dst_path, Vec::new())
+                        .await?,
+                );
+                Ok(entries)
             }
             (None, Some(dst_attr)) if dst_attr.is_dir() => {
                 let dst_files = dst.glob(&dst_path).await?;
-                ctx.check_copy_dir2(src_path, Vec::new(), dst_path, dst_files)
-                    .await
+                let mut entries =
+                    Vec::from_iter(ctx.select_dst(&src_path, &dst_path, dst_attr).await?);
+                entries.extend(
+                    ctx.check_copy_dir2(src_path, Vec::new(), dst_path, dst_files)
+                        .await?,
+                );
+                Ok(entries)
             }
             (Some(src_attr), Some(dst_attr)) if !src_attr.is_dir() && !dst_attr.is_dir() => {
                 Ok(Vec::from_iter(
@@ -320,47 +560,242 @@ impl<'a> SyncContext<'a> {
             }
         }
     }
-    pub async fn execute(&self, entres: &[Entry]) -> Result<bool> {
+    fn is_unreachable(&self, duid: &str) -> bool {
+        self.unreachable
+            .lock()
+            .expect("unreachable poisoned")
+            .contains(duid)
+    }
+    /// Copy `sp` to `dp`, `duid` is the uid of `dst`.
+    #[allow(clippy::too_many_arguments)]
+    async fn copy(
+        &self,
+        src: &User,
+        sp: &U8Path,
+        dst: &User,
+        duid: &str,
+        dp: &U8Path,
+        known: (Option<u64>, Option<i64>),
+        tracker: &Tracker,
+    ) -> Result<()> {
+        // the scan already knows the source, only entries built elsewhere need a stat
+        let (size, mtime) = match known {
+            (Some(size), Some(mtime)) => (size, mtime),
+            _ => {
+                let Some(attr) = src.file_attributes(sp).await?.1 else {
+                    bail!("{sp} not found")
+                };
+                (
+                    attr.size.unwrap_or_default(),
+                    attr.mtime.unwrap_or_default() as i64,
+                )
+            }
+        };
+        let file = tracker.file(sp.as_str(), Some(size));
+        let limited = self.rate_limit.is_some() || src.limiter.is_some() || dst.limiter.is_some();
+        if self.direct && !limited && direct::supported(src, dst) && !self.is_unreachable(duid) {
+            match direct::try_direct(src, sp, dst, dp).await {
+                Ok(true) => {
+                    file.add(size);
+                    return Ok(());
+                }
+                Ok(false) => {
+                    info!("{duid} unreachable from the other side, relay instead");
+                    self.unreachable
+                        .lock()
+                        .expect("unreachable poisoned")
+                        .insert(duid.to_string());
+                }
+                Err(e) => warn!("direct transfer {dp} failed, fall back to relay: {e}"),
+            }
+        }
+        if let Some(min_size) = self.delta {
+            match delta::try_delta(src, sp, dst, dp, min_size, Some(&file)).await {
+                Ok(true) => return Ok(()),
+                Ok(false) => debug!("no delta transfer for {dp}"),
+                Err(e) => {
+                    warn!("delta transfer {dp} failed, fall back to full copy: {e}");
+                    file.reset();
+                }
+            }
+        }
+        if size >= resume::MIN_SIZE {
+            let ident = format!("{size}:{mtime}");
+            let db = &self.ctx.db;
+            return resume::copy(db, duid, &ident, src, sp, dst, dp, Some(&file)).await;
+        }
+        try_copy(src, sp, dst, dp, Some(&file)).await
+    }
+    /// Create a missing directory, with the mode of the other side if `mode` is set, or remove
+    /// an empty one.
+    async fn apply_dir(&self, src: &User, dst: &User, entry: &Entry, mode: bool) -> Result<()> {
+        let (from, fp, to, tp) = match entry.opt {
+            Opt::UPLOAD => (src, &entry.src, dst, &entry.dst),
+            Opt::DOWNLOAD => (dst, &entry.dst, src, &entry.src),
+            Opt::DELETEDST | Opt::DELETESRC => {
+                let (user, path) = if entry.opt == Opt::DELETEDST {
+                    (dst, &entry.dst)
+                } else {
+                    (src, &entry.src)
+                };
+                if !user.rmdir(path).await? {
+                    info!("keep non-empty directory {path}");
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        if !mode {
+            return to.create_dir(tp, FileAttributes::default()).await;
+        }
+        let Some(attr) = from.file_attributes(fp).await?.1 else {
+            bail!("{fp} not found")
+        };
+        to.create_dir(tp, attr).await
+    }
+    /// Apply one entry, returning the source and destination mtimes to record for it.
+    async fn apply(
+        &self,
+        src: &User,
+        dst: &User,
+        entry: &Entry,
+        tracker: &Tracker,
+    ) -> Result<Option<(i64, i64)>> {
+        if entry.is_dir {
+            self.apply_dir(src, dst, entry, true).await?;
+            return Ok(None);
+        }
+        let record = match entry.opt {
+            Opt::OVERWRITE | Opt::UPLOAD => {
+                if let Some(backup) = &entry.backup {
+                    dst.rename(&entry.dst, backup).await?;
+                }
+                let known = (entry.src_size, entry.src_attr);
+                self.copy(src, &entry.src, dst, self.duid, &entry.dst, known, tracker)
+                    .await?;
+                Some(tokio::try_join!(
+                    mtime(src, &entry.src, entry.src_attr),
+                    mtime(dst, &entry.dst, None)
+                )?)
+            }
+            Opt::UPDATE | Opt::DOWNLOAD => {
+                if let Some(backup) = &entry.backup {
+                    src.rename(&entry.src, backup).await?;
+                }
+                let known = (entry.dst_size, entry.dst_attr);
+                self.copy(dst, &entry.dst, src, self.suid, &entry.src, known, tracker)
+                    .await?;
+                Some(tokio::try_join!(
+                    mtime(src, &entry.src, None),
+                    mtime(dst, &entry.dst, entry.dst_attr)
+                )?)
+            }
+            Opt::DELETEDST => {
+                self.ctx.db.del(self.duid, entry.dst.as_str()).await?;
+                dst.rm(&entry.dst).await?;
+                None
+            }
+            Opt::DELETESRC => {
+                self.ctx.db.del(self.duid, entry.src.as_str()).await?;
+                src.rm(&entry.src).await?;
+                None
+            }
+            _ => None,
+        };
+        Ok(record)
+    }
+    /// Apply all entries with bounded concurrency.
+    ///
+    /// A failed entry doesn't stop the others; the returned results are in the same order as
+    /// `entries`. The cache records of all successful transfers are written in one batch.
+    /// Directories are created first and get their modes last, so a read-only mode can't block
+    /// the copies into them. They are removed last and deepest first, so they are only gone if
+    /// the deletions of their content emptied them.
+    pub async fn execute_each(&self, entries: &[Entry]) -> Result<Vec<Result<()>>> {
         let src = self.ctx.get_user(self.suid)?;
         let dst = self.ctx.get_user(self.duid)?;
-        for entry in entres {
-            match entry.opt {
-                Opt::OVERWRITE | Opt::UPLOAD => {
-                    try_copy(src, &entry.src, dst, &entry.dst).await?;
-                    let src_mtime = match entry.src_attr {
-                        Some(t) => t,
-                        None => src.get_mtime(&entry.src).await?.expect("get mtime"),
+        let copies = entries
+            .iter()
+            .filter(|e| {
+                !e.is_dir
+                    && e.opt
+                        .intersects(Opt::OVERWRITE | Opt::UPDATE | Opt::UPLOAD | Opt::DOWNLOAD)
+            })
+            .count();
+        let (dirs, others): (Vec<_>, Vec<_>) = (0..entries.len()).partition(|&i| entries[i].is_dir);
+        let (mut rmdirs, mut mkdirs): (Vec<_>, Vec<_>) = dirs
+            .into_iter()
+            .partition(|&i| entries[i].opt.intersects(Opt::DELETEDST | Opt::DELETESRC));
+        let depth = |i: &usize| {
+            let entry = &entries[*i];
+            let path = if matches!(entry.opt, Opt::DELETEDST | Opt::UPLOAD) {
+                &entry.dst
+            } else {
+                &entry.src
+            };
+            path.components().count()
+        };
+        mkdirs.sort_by_key(depth);
+        let (bulk, mut others): (Vec<_>, Vec<_>) = match self.bulk {
+            Some(_) => others
+                .into_iter()
+                .partition(|&i| bulk::eligible(&entries[i])),
+            None => (Vec::new(), others),
+        };
+        let tracker = Tracker::new(format!("sync {} -> {}", self.suid, self.duid), copies)
+            .with_limit(self.rate_limit);
+        let tracker = &tracker;
+        let mut results = tracker
+            .run(&*self.ctx.interactor, async {
+                let mut results = Vec::new();
+                let mut created = Vec::new();
+                for i in mkdirs {
+                    match self.apply_dir(src, dst, &entries[i], false).await {
+                        Ok(()) => created.push(i),
+                        Err(e) => results.push((i, Err(e))),
                     }
-                    .to_string();
-                    let dst_mtime = dst
-                        .get_mtime(&entry.dst)
-                        .await?
-                        .expect("get mtime")
-                        .to_string();
-                    debug!(
-                        "set db {} : {} = {}, {}",
-                        self.duid,
-                        entry.dst.as_str(),
-                        src_mtime,
-                        dst_mtime
-                    );
-                    self.ctx
-                        .db
-                        .set(self.duid, entry.dst.as_str(), &src_mtime, &dst_mtime)
-                        .await?;
                 }
-                Opt::UPDATE | Opt::DOWNLOAD => {
-                    try_copy(dst, &entry.dst, src, &entry.src).await?;
-                    let src_mtime = src
-                        .get_mtime(&entry.src)
-                        .await?
-                        .expect("get mtime")
-                        .to_string();
-                    let dst_mtime = match entry.dst_attr {
-                        Some(t) => t,
-                        None => dst.get_mtime(&entry.dst).await?.expect("get mtime"),
+                if let (Some(compression), false) = (self.bulk, bulk.is_empty()) {
+                    let files = bulk
+                        .iter()
+                        .map(|&i| (entries[i].src.as_path(), entries[i].dst.as_path()))
+                        .collect::<Vec<_>>();
+                    match bulk::transfer(src, dst, &files, compression, tracker).await {
+                        Ok(mtimes) => results.extend(bulk.into_iter().zip(mtimes).map(|(i, t)| {
+                            // tar restores the mtime of the source
+                            (i, Ok(Some((t, t))))
+                        })),
+                        Err(e) => {
+                            warn!("bulk transfer failed, copy file by file: {e}");
+                            others.extend(bulk);
+                        }
                     }
-                    .to_string();
+                }
+                let each = stream::iter(others)
+                    .map(|i| async move { (i, self.apply(src, dst, &entries[i], tracker).await) })
+                    .buffered(self.concurrency)
+                    .collect::<Vec<_>>()
+                    .await;
+                results.extend(each);
+                for &i in created.iter().rev() {
+                    let res = self.apply(src, dst, &entries[i], tracker).await;
+                    results.push((i, res));
+                }
+                results
+            })
+            .await;
+        rmdirs.sort_by_key(|i| std::cmp::Reverse(depth(i)));
+        for i in rmdirs {
+            results.push((i, self.apply(src, dst, &entries[i], tracker).await));
+        }
+        results.sort_by_key(|(i, _)| *i);
+        let mut records = Vec::new();
+        let results = results
+            .into_iter()
+            .map(|(_, res)| res)
+            .zip(entries)
+            .map(|(res, entry)| {
+                if let Some((src_mtime, dst_mtime)) = res? {
                     debug!(
                         "set db {} : {} = {}, {}",
                         self.duid,
@@ -368,23 +803,43 @@ impl<'a> SyncContext<'a> {
                         src_mtime,
                         dst_mtime
                     );
-                    self.ctx
-                        .db
-                        .set(self.duid, entry.dst.as_str(), &src_mtime, &dst_mtime)
-                        .await?;
+                    let meta = SyncMeta {
+                        src_uid: self.suid.to_string(),
+                        src: entry.src.to_string(),
+                    };
+                    // version and latest keep the mtimes for readers of `DB::get`
+                    let record = Record {
+                        mtime: Some(dst_mtime),
+                        src_mtime: Some(src_mtime),
+                        meta: Some(serde_json::to_string(&meta)?),
+                        ..Record::new(OpKind::Sync, src_mtime.to_string(), dst_mtime.to_string())
+                    };
+                    records.push((entry.dst.as_str(), record));
                 }
-                Opt::DELETEDST => {
-                    self.ctx.db.del(self.duid, entry.dst.as_str()).await?;
-                    dst.rm(&entry.dst).await?;
-                }
-                Opt::DELETESRC => {
-                    self.ctx.db.del(self.duid, entry.src.as_str()).await?;
-                    src.rm(&entry.src).await?;
-                }
-                _ => {}
+                Ok(())
+            })
+            .collect();
+        self.ctx.db.set_records(self.duid, &records).await?;
+        Ok(results)
+    }
+    /// Apply all entries, reporting each failure through the interactor.
+    ///
+    /// Returns `false` if any entry failed.
+    pub async fn execute(&self, entries: &[Entry]) -> Result<bool> {
+        let mut suc = true;
+        for (entry, res) in entries.iter().zip(self.execute_each(entries).await?) {
+            if let Err(e) = res {
+                suc = false;
+                self.ctx
+                    .interactor
+                    .log(format!(
+                        "sync {}:{} -> {}:{} failed: {e}",
+                        self.suid, entry.src, self.duid, entry.dst
+                    ))
+                    .await;
             }
         }
-        Ok(true)
+        Ok(suc)
     }
 }
 #[cfg(test)]
@@ -392,7 +847,7 @@ mod tests {
     use std::path::Path;
 
     use crate::{
-        Context,
+        Context, OpKind, Record,
         db::{MultiDB, Sqlite},
         dev::User,
         interactor::TermInteractor,
@@ -400,9 +855,10 @@ mod tests {
 
     use assert_fs::{TempDir, prelude::*};
     use dv_api::multi::Config;
+    use sha2::{Digest, Sha256};
 
     use super::Opt;
-    use super::SyncContext;
+    use super::{Entry as SyncEntry, SyncContext};
 
     fn mtime(path: &Path) -> u64 {
         path.metadata()
@@ -753,4 +1209,366 @@ mod tests {
             both_fixture(ops, *db, res, i).await;
         }
     }
+    #[tokio::test]
+    async fn concurrent() {
+        let files = [("f0", "f0"), ("f1", "f1"), ("d/f2", "f2"), ("d/f3", "f3")];
+        let (ctx, dir) = tenv(&files, &[]).await;
+        let mut ctx = SyncContext::new(&ctx, "this", "this", &[Opt::UPLOAD]);
+        ctx.set_concurrency(2);
+        let mut entries = ctx.scan("src", "dst").await.unwrap();
+        // `dst` and `dst/d` are created as well
+        assert_eq!(entries.len(), files.len() + 2);
+        entries.insert(
+            1,
+            SyncEntry {
+                src: dir.child("src/missing").to_str().unwrap().into(),
+                dst: dir.child("dst/missing").to_str().unwrap().into(),
+                opt: Opt::UPLOAD,
+                ..Default::default()
+            },
+        );
+        let res = ctx.execute_each(&entries).await.unwrap();
+        assert_eq!(res.len(), entries.len());
+        assert!(res[1].is_err());
+        assert_eq!(res.iter().filter(|r| r.is_ok()).count(), files.len() + 2);
+        assert!(!ctx.execute(&entries).await.unwrap());
+        for (name, content) in files {
+            let dst = dir.child("dst").child(name);
+            dst.assert(content);
+            let db = ctx
+                .ctx
+                .db
+                .get_as::<u64>("this", dst.to_str().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                db,
+                Some((mtime(&dir.child("src").child(name)), mtime(&dst)))
+            );
+        }
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dirs() {
+        use std::os::unix::fs::PermissionsExt;
+        let (ctx, dir) = tenv(&[("a/f0", "f0")], &[("old/sub/f1", "f1")]).await;
+        // a read-only directory gets its mode after its content is copied
+        let readonly = std::fs::Permissions::from_mode(0o500);
+        std::fs::set_permissions(dir.child("src/a"), readonly).unwrap();
+        dir.child("src/empty").create_dir_all().unwrap();
+        let perm = std::fs::Permissions::from_mode(0o700);
+        std::fs::set_permissions(dir.child("src/empty"), perm).unwrap();
+        dir.child("dst/old/empty").create_dir_all().unwrap();
+        let ctx = SyncContext::new(&ctx, "this", "this", &[Opt::UPLOAD, Opt::DELETEDST]);
+        let entries = ctx.scan("src", "dst").await.unwrap();
+        let dirs = entries.iter().filter(|e| e.is_dir).count();
+        // src/a, src/empty, dst/old, dst/old/sub, dst/old/empty
+        assert_eq!(dirs, 5);
+        assert!(ctx.execute(&entries).await.unwrap());
+        dir.child("dst/a/f0").assert("f0");
+        let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir.child("dst/a")), 0o500);
+        for d in ["src/a", "dst/a"] {
+            std::fs::set_permissions(dir.child(d), std::fs::Permissions::from_mode(0o700)).unwrap();
+        }
+        let empty = dir.child("dst/empty");
+        assert!(empty.is_dir());
+        assert_eq!(
+            empty.metadata().unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        assert!(!dir.child("dst/old").exists());
+        // a directory that still has content after the deletions stays
+        let (ctx, dir) = tenv(&[], &[("d/f0", "f0")]).await;
+        dir.child("src").create_dir_all().unwrap();
+        let ctx = SyncContext::new(&ctx, "this", "this", &[Opt::UPLOAD, Opt::DELETEDST]);
+        let mut entries = ctx.scan("src", "dst").await.unwrap();
+        entries.retain(|e| e.is_dir);
+        assert!(ctx.execute(&entries).await.unwrap());
+        dir.child("dst/d/f0").assert("f0");
+    }
+    #[tokio::test]
+    async fn watch() {
+        let (ctx, dir) = tenv(&[("f0", "f0")], &[]).await;
+        let mut ctx = SyncContext::new(&ctx, "this", "this", &[Opt::UPLOAD, Opt::DELETEDST]);
+        ctx.set_debounce(std::time::Duration::from_millis(50));
+        let wait = |path: &'static str, exists: bool| {
+            let path = dir.child(path);
+            async move {
+                while path.exists() != exists {
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+            }
+        };
+        let changes = async {
+            wait("dst/f0", true).await;
+            dir.child("src/d/f1").write_str("f1").unwrap();
+            wait("dst/d/f1", true).await;
+            std::fs::remove_file(dir.child("src/f0")).unwrap();
+            wait("dst/f0", false).await;
+        };
+        tokio::select! {
+            res = ctx.watch("src", "dst") => panic!("watch stopped: {res:?}"),
+            res = tokio::time::timeout(std::time::Duration::from_secs(10), changes) => {
+                res.expect("changes not synced")
+            }
+        }
+        dir.child("dst/d/f1").assert("f1");
+    }
+    #[tokio::test]
+    async fn policy() {
+        use super::Policy;
+        let (ctx, dir) = tenv(&[("f0.txt", "src"), ("f1", "f1")], &[("f0.txt", "dst")]).await;
+        let src = dir.child("src/f0.txt");
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let mut ctx = SyncContext::new(&ctx, "this", "this", &[]);
+        ctx.set_policy(Some(Policy::Fail));
+        assert!(ctx.scan("src", "dst").await.is_err());
+        ctx.set_policy(Some(Policy::KeepBoth));
+        ctx.add_policy("**/f1", Policy::Dest).unwrap();
+        let entries = ctx.scan("src", "dst").await.unwrap();
+        assert_eq!(entries.len(), 2);
+        let f0 = entries
+            .iter()
+            .find(|e| e.src == src.to_str().unwrap())
+            .unwrap();
+        assert_eq!((f0.opt, f0.policy), (Opt::UPDATE, Some(Policy::KeepBoth)));
+        let f1 = entries.iter().find(|e| e.opt == Opt::DELETESRC).unwrap();
+        assert_eq!(f1.policy, Some(Policy::Dest));
+        assert!(ctx.execute(&entries).await.unwrap());
+        src.assert("dst");
+        dir.child("src/f0.this.txt").assert("src");
+        assert!(!dir.child("src/f1").exists());
+    }
+    #[tokio::test]
+    async fn fanout() {
+        use super::FanOut;
+        let (mut ctx, dir) = tenv(&[("f0", "f0"), ("d/f1", "f1")], &[]).await;
+        for uid in ["d0", "d1"] {
+            let mut cfg = Config::default();
+            cfg.set("mount", dir.child(uid).to_string_lossy());
+            ctx.add_user(uid.to_string(), User::local(cfg).await.unwrap())
+                .await
+                .unwrap();
+        }
+        // a file where the source has a directory can't be synced
+        dir.child("d1/dst").write_str("file").unwrap();
+        // and an unknown device fails on its own
+        let fan = FanOut::new(&ctx, "this", ["d0", "d1", "gone"], &[Opt::UPLOAD]);
+        let plans = fan.scan("src", "dst").await.unwrap();
+        assert_eq!(plans.len(), 3);
+        assert_eq!(plans[0].duid, "d0");
+        assert!(plans[1].entries.is_err());
+        assert!(plans[2].entries.is_err());
+        let results = fan.execute(&plans).await;
+        assert_eq!(results[0].duid, "d0");
+        assert!(
+            results[0]
+                .results
+                .as_ref()
+                .unwrap()
+                .iter()
+                .all(|r| r.is_ok())
+        );
+        assert!(results[1].results.is_err());
+        assert!(results[2].results.is_err());
+        dir.child("d0/dst/f0").assert("f0");
+        dir.child("d0/dst/d/f1").assert("f1");
+        let db = ctx
+            .db
+            .get("d0", dir.child("d0/dst/f0").to_str().unwrap())
+            .await
+            .unwrap();
+        assert!(db.is_some());
+    }
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn bulk() {
+        use super::Compression;
+        use std::os::unix::fs::PermissionsExt;
+        let long = format!("{}/f2", "d".repeat(120));
+        for compression in [Compression::None, Compression::Gzip] {
+            let (ctx, dir) = tenv(&[("f0", "f0"), ("d/f1", "f1"), (&long, "f2")], &[]).await;
+            let perm = std::fs::Permissions::from_mode(0o750);
+            std::fs::set_permissions(dir.child("src/f0"), perm).unwrap();
+            let mut ctx = SyncContext::new(&ctx, "this", "this", &[Opt::UPLOAD]);
+            ctx.set_bulk(Some(compression));
+            let entries = ctx.scan("src", "dst").await.unwrap();
+            assert!(ctx.execute(&entries).await.unwrap());
+            dir.child("dst/d/f1").assert("f1");
+            dir.child("dst").child(&long).assert("f2");
+            let f0 = dir.child("dst/f0");
+            f0.assert("f0");
+            assert_eq!(f0.metadata().unwrap().permissions().mode() & 0o777, 0o750);
+            assert_eq!(mtime(&f0), mtime(&dir.child("src/f0")));
+            let db = ctx.ctx.db.get("this", f0.to_str().unwrap()).await.unwrap();
+            let t = mtime(&f0).to_string();
+            assert_eq!(db, Some((t.clone(), t)));
+            let record = ctx.ctx.db.get_record("this", f0.to_str().unwrap()).await;
+            let record = record.unwrap().unwrap();
+            let t = Some(mtime(&f0) as i64);
+            assert_eq!((record.src_mtime, record.mtime), (t, t));
+        }
+    }
+    /// Needs an `ssh` client on the `PATH` and fails without one: the two SSH hosts are servers
+    /// in this process, and the first logs into the second with the test key.
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn direct() {
+        use std::os::unix::fs::PermissionsExt;
+        // the source host pushes with the ssh client of this machine
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let ssh = std::env::split_paths(&path)
+            .map(|p| p.join("ssh"))
+            .find(|p| p.is_file())
+            .expect("no ssh client on the PATH, the direct test needs one");
+        let (mut ctx, dir) = tenv(&[("f0", "f0"), ("f1", "f1")], &[]).await;
+        let key = dir.child("key");
+        key.write_str(super::sshd::KEY).unwrap();
+        std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let log = dir.child("ssh.log");
+        let wrapper = dir.child("bin/ssh");
+        wrapper
+            .write_str(&format!(
+                "#!/bin/sh\necho \"$@\" >> '{}'\nexec '{}' -F /dev/null -i '{}' \
+                 -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null \
+                 -o LogLevel=ERROR \"$@\"\n",
+                log.display(),
+                ssh.display(),
+                key.display()
+            ))
+            .unwrap();
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // a port nothing listens on once the listener is dropped
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .unwrap()
+            .port();
+        for uid in ["s", "d", "far"] {
+            let port = super::sshd::serve(dir.child("bin").to_path_buf()).await;
+            let mut cfg = Config::default();
+            cfg.set("host", "127.0.0.1");
+            cfg.set("port", port.to_string());
+            cfg.set("user", "dv");
+            cfg.set("passwd", "dv");
+            if uid == "far" {
+                // the source can't reach it on its own
+                cfg.set("ssh_addr", "dv@127.0.0.1");
+                cfg.set("ssh_port", closed.to_string());
+            }
+            ctx.add_user(uid.to_string(), User::ssh(cfg).await.unwrap())
+                .await
+                .unwrap();
+        }
+        let path = |p: &str| dir.child(p).to_str().unwrap().to_string();
+
+        let mut sync = SyncContext::new(&ctx, "s", "d", &[Opt::UPLOAD]);
+        sync.set_direct(true);
+        let entries = sync.scan(&path("src"), &path("dst")).await.unwrap();
+        assert!(sync.execute(&entries).await.unwrap());
+        assert!(!sync.is_unreachable("d"));
+        dir.child("dst/f0").assert("f0");
+        dir.child("dst/f1").assert("f1");
+        let pushed = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(pushed.lines().count(), 2);
+
+        // the destination is reported unreachable and the controller relays instead
+        let mut sync = SyncContext::new(&ctx, "s", "far", &[Opt::UPLOAD]);
+        sync.set_direct(true);
+        let entries = sync.scan(&path("src/f0"), &path("far/f0")).await.unwrap();
+        assert!(sync.execute(&entries).await.unwrap());
+        assert!(sync.is_unreachable("far"));
+        dir.child("far/f0").assert("f0");
+    }
+    #[tokio::test]
+    async fn shared_limiter() {
+        let (mut ctx, _dir) = tenv(&[], &[]).await;
+        for (uid, hid) in [("a", "h"), ("b", "h"), ("c", "other")] {
+            let mut cfg = Config::default();
+            cfg.set("hid", hid);
+            cfg.set("rate_limit", "1M");
+            ctx.add_user(uid.to_string(), User::local(cfg).await.unwrap())
+                .await
+                .unwrap();
+        }
+        let user = |uid| ctx.get_user(uid).unwrap();
+        // a transfer within one device is charged on the read side only
+        assert!(user("b").write_limiter(user("a")).is_none());
+        assert!(user("c").write_limiter(user("a")).is_some());
+        assert!(user("a").write_limiter(user("c")).is_some());
+    }
+    #[tokio::test]
+    async fn delta() {
+        let old = (0..2000).map(|i| format!("line {i}\n")).collect::<String>();
+        let new = old.replace("line 1000\n", "changed\n") + "appended\n";
+        let (ctx, dir) = tenv(&[("f0", &new)], &[("f0", &old)]).await;
+        let mut ctx = SyncContext::new(&ctx, "this", "this", &[Opt::OVERWRITE]);
+        ctx.set_delta(Some(0));
+        let entries = ctx.scan("src/f0", "dst/f0").await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(ctx.execute(&entries).await.unwrap());
+        dir.child("dst/f0").assert(new.as_str());
+        assert!(!dir.child("dst/f0.dvdelta").exists());
+        assert!(!dir.child("dst/f0.dvtmp").exists());
+    }
+    async fn resume_fixture(part: &str, recorded: &str, expected: &str) {
+        let content = "0123456789abcdef".repeat(128 * 1024);
+        let (ctx, dir) = tenv(&[("f0", &content)], &[("f0.dvpart", part)]).await;
+        let src = dir.child("src/f0");
+        let dst = dir.child("dst/f0");
+        let ident = format!("{}:{}", content.len(), mtime(&src));
+        let record = Record {
+            size: Some(recorded.len() as u64),
+            hash: Some(format!("sha256:{:x}", Sha256::digest(recorded.as_bytes()))),
+            ..Record::new(OpKind::Resume, ident, "")
+        };
+        let part = dir.child("dst/f0.dvpart");
+        ctx.db
+            .set_record("this", part.to_str().unwrap(), &record)
+            .await
+            .unwrap();
+        let ctx = SyncContext::new(&ctx, "this", "this", &[Opt::UPLOAD]);
+        let entries = ctx.scan("src/f0", "dst/f0").await.unwrap();
+        assert!(ctx.execute(&entries).await.unwrap());
+        dst.assert(format!("{expected}{}", &content[expected.len()..]));
+        assert!(!part.exists());
+        let db = ctx
+            .ctx
+            .db
+            .get("this", part.to_str().unwrap())
+            .await
+            .unwrap();
+        assert!(db.is_none());
+    }
+    #[tokio::test]
+    async fn resume() {
+        let prefix = "0123456789abcdef".repeat(1024);
+        // the part is only continued if both it and the source prefix match the record
+        let fake = "x".repeat(prefix.len());
+        resume_fixture(&fake, &prefix, "").await;
+        resume_fixture(&prefix, &prefix, &prefix).await;
+        // the source changed since the part was written
+        resume_fixture(&fake, &fake, "").await;
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn resume_keeps_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let content = "0123456789abcdef".repeat(128 * 1024);
+        let (ctx, dir) = tenv(&[("f0", &content)], &[("f0", "old")]).await;
+        let dst = dir.child("dst/f0");
+        std::fs::set_permissions(&dst, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let ctx = SyncContext::new(&ctx, "this", "this", &[Opt::OVERWRITE]);
+        let entries = ctx.scan("src/f0", "dst/f0").await.unwrap();
+        assert!(ctx.execute(&entries).await.unwrap());
+        dst.assert(content.as_str());
+        let mode = std::fs::metadata(&dst).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
 }
@@ -0,0 +1,240 @@
+//! Bulk transfers of many files through one tar stream.
+//!
+//! The tar is generated here from the source files and piped into `tar -x` on the destination,
+//! optionally compressed by a local `gzip`/`zstd` and decompressed by the same tool over there.
+//! Member names are the absolute destination paths, so the archive is unpacked at `/`.
+use std::process::Stdio;
+
+use tracing::debug;
+
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::super::dev::*;
+use crate::{progress::Tracker, throttle::Throttled};
+
+const BLOCK: usize = 512;
+/// Longest value the octal `size` and `mtime` header fields hold.
+const MAX_OCTAL: u64 = 0o77777777777;
+
+/// How the tar stream of a bulk transfer is compressed on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The local compressor, `None` if the tar is sent as is.
+    fn pack(self) -> Option<&'static [&'static str]> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some(&["gzip", "-c"]),
+            Compression::Zstd => Some(&["zstd", "-c", "-q"]),
+        }
+    }
+    /// The shell command unpacking the stream on the destination.
+    fn unpack(self) -> &'static str {
+        match self {
+            Compression::None => "tar -xpo -f - -C /",
+            Compression::Gzip => "gzip -dc | tar -xpo -f - -C /",
+            Compression::Zstd => "zstd -dcq | tar -xpo -f - -C /",
+        }
+    }
+}
+
+/// Whether `entry` can be part of a bulk transfer.
+pub fn eligible(entry: &super::Entry) -> bool {
+    use super::Opt;
+    !entry.is_dir
+        && matches!(entry.opt, Opt::UPLOAD | Opt::OVERWRITE)
+        && entry.backup.is_none()
+        && entry.dst.has_root()
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let s = format!("{value:0width$o}", width = field.len() - 1);
+    field[..s.len()].copy_from_slice(s.as_bytes());
+}
+
+fn header(name: &[u8], mode: u32, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut h = [0; BLOCK];
+    h[..name.len()].copy_from_slice(name);
+    octal(&mut h[100..108], mode as u64);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size.min(MAX_OCTAL));
+    octal(&mut h[136..148], mtime.min(MAX_OCTAL));
+    h[156] = kind;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[148..156].fill(b' ');
+    let sum: u32 = h.iter().map(|&b| b as u32).sum();
+    let sum = format!("{sum:06o}\0 ");
+    h[148..156].copy_from_slice(sum.as_bytes());
+    h
+}
+
+/// A pax extended header record, its length prefix counts itself.
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {key}={value}\n");
+    let mut len = body.len() + 1;
+    loop {
+        let total = len.to_string().len() + body.len();
+        if total == len {
+            break format!("{len}{body}");
+        }
+        len = total;
+    }
+}
+
+fn padding(len: u64) -> usize {
+    (BLOCK - (len % BLOCK as u64) as usize) % BLOCK
+}
+
+/// Write the tar header of a regular file, preceded by a pax header if the name or the size
+/// doesn't fit into it.
+async fn write_header<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    name: &str,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+) -> Result<()> {
+    let mut pax = String::new();
+    if name.len() > 100 {
+        pax.push_str(&pax_record("path", name));
+    }
+    if size > MAX_OCTAL {
+        pax.push_str(&pax_record("size", &size.to_string()));
+    }
+    if !pax.is_empty() {
+        w.write_all(&header(
+            b"././@PaxHeader",
+            0o644,
+            pax.len() as u64,
+            mtime,
+            b'x',
+        ))
+        .await?;
+        w.write_all(pax.as_bytes()).await?;
+        w.write_all(&[0; BLOCK][..padding(pax.len() as u64)])
+            .await?;
+    }
+    let short = &name.as_bytes()[..name.len().min(100)];
+    w.write_all(&header(short, mode, size, mtime, b'0')).await?;
+    Ok(())
+}
+
+/// Write `files` as a tar to `w`, returning the mtime each file was archived with.
+async fn write_tar<W: AsyncWrite + Unpin>(
+    src: &User,
+    files: &[(&U8Path, &U8Path)],
+    w: &mut W,
+    tracker: &Tracker,
+) -> Result<Vec<i64>> {
+    let mut mtimes = Vec::with_capacity(files.len());
+    for (sp, dp) in files {
+        let Some(attr) = src.file_attributes(sp).await?.1 else {
+            bail!("{sp} not found")
+        };
+        let size = attr.size.unwrap_or_default();
+        let mtime = attr.mtime.unwrap_or_default();
+        let mode = attr.permissions.unwrap_or(0o644) & 0o7777;
+        let name = dp.as_str().trim_start_matches('/');
+        write_header(w, name, mode, size, mtime as u64).await?;
+        let file = tracker.file(sp.as_str(), Some(size));
+        let from = src.open(sp, OpenFlags::READ).await?;
+        let copied = tokio::io::copy(&mut file.reader(from).take(size), w).await?;
+        if copied != size {
+            bail!("{sp} shrank while archiving it");
+        }
+        w.write_all(&[0; BLOCK][..padding(size)]).await?;
+        mtimes.push(mtime as i64);
+    }
+    w.write_all(&[0; 2 * BLOCK]).await?;
+    w.flush().await?;
+    Ok(mtimes)
+}
+
+/// Pipe the tar through the local compressor `program` into `w`.
+async fn write_compressed<W: AsyncWrite + Unpin>(
+    program: &[&str],
+    src: &User,
+    files: &[(&U8Path, &U8Path)],
+    w: &mut W,
+    tracker: &Tracker,
+) -> Result<Vec<i64>> {
+    let mut child = tokio::process::Command::new(program[0])
+        .args(&program[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let pack = async move {
+        let mtimes = write_tar(src, files, &mut stdin, tracker).await?;
+        stdin.shutdown().await?;
+        Ok::<_, anyhow::Error>(mtimes)
+    };
+    let forward = async {
+        tokio::io::copy(&mut stdout, w).await?;
+        Ok(())
+    };
+    let (mtimes, ()) = tokio::try_join!(pack, forward)?;
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("{} exited with {status}", program[0]);
+    }
+    Ok(mtimes)
+}
+
+/// Transfer `files`, pairs of source and absolute destination paths, as one tar stream.
+///
+/// Modes and mtimes are preserved, the returned mtimes are in the order of `files`.
+pub async fn transfer(
+    src: &User,
+    dst: &User,
+    files: &[(&U8Path, &U8Path)],
+    compression: Compression,
+    tracker: &Tracker,
+) -> Result<Vec<i64>> {
+    let unpack = compression.unpack();
+    debug!("bulk transfer {} files through {unpack}", files.len());
+    let (mut ctl, mut writer, mut reader) = dst.spawn(Script::sh(unpack)).await?.destruct();
+    // a limiter shared with the source is charged by reading the files
+    let mut w: Box<dyn AsyncWrite + Unpin + Send + '_> = match dst.write_limiter(src) {
+        Some(limiter) => Box::new(Throttled::new(&mut writer, limiter.clone())),
+        None => Box::new(&mut writer),
+    };
+    let mtimes = match compression.pack() {
+        None => write_tar(src, files, &mut w, tracker).await?,
+        Some(program) => write_compressed(program, src, files, &mut w, tracker).await?,
+    };
+    drop(w);
+    writer.eof().await?;
+    drop(writer);
+    tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+    let code = ctl.wait().await?;
+    if code != 0 {
+        bail!("tar exited with {code}");
+    }
+    Ok(mtimes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pax() {
+        assert_eq!(pax_record("path", "a"), "9 path=a\n");
+        let long = "x".repeat(93);
+        let record = pax_record("path", &long);
+        assert_eq!(record.len(), 103);
+        assert!(record.starts_with("103 "));
+    }
+}
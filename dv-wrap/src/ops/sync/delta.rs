@@ -0,0 +1,424 @@
+//! rsync-like delta transfer.
+//!
+//! The destination computes a block signature (adler32 + sha256 per block) with a small python
+//! helper, the source side matches its content against it with a rolling checksum and uploads
+//! only the unmatched bytes, and the helper finally rebuilds the file from the old blocks and
+//! the uploaded delta.
+//!
+//! Delta format: a sequence of `C <u64 block index>` and `L <u32 length> <bytes>` records,
+//! terminated by `E <sha256 of the new content>`. All integers are big endian.
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tracing::{debug, warn};
+
+use super::{super::dev::*, shell_quote};
+use crate::progress::FileGuard;
+
+const HELPER: &str = r#"import hashlib, os, shutil, struct, sys, zlib
+mode, bs, path = sys.argv[1], int(sys.argv[2]), sys.argv[3]
+if mode == "sig":
+    out = ["dv-delta %d" % bs]
+    with open(path, "rb") as f:
+        for b in iter(lambda: f.read(bs), b""):
+            out.append("%d %d %s" % (zlib.adler32(b), len(b), hashlib.sha256(b).hexdigest()))
+    print("\n".join(out))
+elif mode == "patch":
+    delta, tmp, h = sys.argv[4], path + ".dvtmp", hashlib.sha256()
+    try:
+        with open(delta, "rb") as d, open(path, "rb") as old, open(tmp, "wb") as new:
+            while True:
+                op = d.read(1)
+                if op == b"C":
+                    old.seek(struct.unpack(">Q", d.read(8))[0] * bs)
+                    b = old.read(bs)
+                elif op == b"L":
+                    b = d.read(struct.unpack(">I", d.read(4))[0])
+                elif op == b"E":
+                    if d.read(32) != h.digest():
+                        sys.exit("checksum mismatch")
+                    break
+                else:
+                    sys.exit("bad delta")
+                h.update(b)
+                new.write(b)
+        shutil.copymode(path, tmp)
+        os.replace(tmp, path)
+        print("dv-delta ok")
+    finally:
+        os.remove(delta)
+        if os.path.exists(tmp):
+            os.remove(tmp)
+"#;
+
+/// Unmatched bytes are flushed as a literal once they reach this size.
+const MAX_LITERAL: usize = 256 * 1024;
+const READ_SIZE: usize = 64 * 1024;
+
+fn helper_script(args: &[&str]) -> String {
+    let mut script = String::from("python3 -");
+    for arg in args {
+        script.push(' ');
+        script.push_str(&shell_quote(arg));
+    }
+    script.push_str(" <<'DV_DELTA'\n");
+    script.push_str(HELPER);
+    script.push_str("DV_DELTA\n");
+    script
+}
+
+/// Pick a block size around the square root of the file size, like rsync does.
+pub fn block_size(size: u64) -> usize {
+    ((size as f64).sqrt() as usize)
+        .next_power_of_two()
+        .clamp(4 * 1024, 128 * 1024)
+}
+
+const MOD: u32 = 65521;
+
+/// Rolling adler32, compatible with zlib's.
+#[derive(Debug, Clone, Copy)]
+struct Adler32 {
+    a: u32,
+    b: u32,
+    n: u32,
+}
+
+impl Adler32 {
+    fn new(block: &[u8]) -> Self {
+        let (mut a, mut b) = (1, 0);
+        for &c in block {
+            a = (a + c as u32) % MOD;
+            b = (b + a) % MOD;
+        }
+        Self {
+            a,
+            b,
+            n: block.len() as u32 % MOD,
+        }
+    }
+    fn roll(&mut self, out: u8, inp: u8) {
+        let (out, inp) = (out as u32, inp as u32);
+        self.a = (self.a + MOD - out + inp) % MOD;
+        self.b = (self.b + MOD - self.n * out % MOD + self.a + MOD - 1) % MOD;
+    }
+    fn hash(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Signature {
+    block_size: usize,
+    /// Full-sized blocks by weak checksum: `(block index, sha256)`.
+    blocks: HashMap<u32, Vec<(u64, [u8; 32])>>,
+}
+
+fn parse_hex(s: &str) -> Option<[u8; 32]> {
+    let mut out = [0; 32];
+    if s.len() != 64 {
+        return None;
+    }
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+impl Signature {
+    fn parse(out: &str) -> Option<Self> {
+        let mut lines = out.lines().skip_while(|l| !l.starts_with("dv-delta "));
+        let block_size = lines
+            .next()?
+            .strip_prefix("dv-delta ")?
+            .trim()
+            .parse()
+            .ok()?;
+        let mut sig = Self {
+            block_size,
+            blocks: HashMap::new(),
+        };
+        for (i, line) in lines.enumerate() {
+            let mut it = line.split_whitespace();
+            let (Some(weak), Some(len), Some(strong)) = (it.next(), it.next(), it.next()) else {
+                return None;
+            };
+            if len.parse::<usize>().ok()? != block_size {
+                continue;
+            }
+            sig.blocks
+                .entry(weak.parse().ok()?)
+                .or_default()
+                .push((i as u64, parse_hex(strong)?));
+        }
+        Some(sig)
+    }
+    #[cfg(test)]
+    fn compute(data: &[u8], block_size: usize) -> Self {
+        let mut sig = Self {
+            block_size,
+            blocks: HashMap::new(),
+        };
+        for (i, b) in data.chunks_exact(block_size).enumerate() {
+            sig.blocks
+                .entry(Adler32::new(b).hash())
+                .or_default()
+                .push((i as u64, Sha256::digest(b).into()));
+        }
+        sig
+    }
+    fn find(&self, weak: u32, block: &[u8]) -> Option<u64> {
+        let candidates = self.blocks.get(&weak)?;
+        let strong: [u8; 32] = Sha256::digest(block).into();
+        candidates
+            .iter()
+            .find_map(|(i, s)| (*s == strong).then_some(*i))
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub matched: u64,
+    pub literal: u64,
+}
+
+async fn write_literal<W: AsyncWrite + Unpin>(
+    out: &mut W,
+    data: &[u8],
+    hasher: &mut Sha256,
+    stats: &mut Stats,
+) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    out.write_u8(b'L').await?;
+    out.write_u32(data.len() as u32).await?;
+    out.write_all(data).await?;
+    hasher.update(data);
+    stats.literal += data.len() as u64;
+    Ok(())
+}
+
+/// Stream the delta of `src` against `sig` into `out`.
+pub async fn generate<R, W>(sig: &Signature, mut src: R, out: W) -> Result<Stats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let bs = sig.block_size;
+    let mut out = BufWriter::with_capacity(READ_SIZE, out);
+    let mut hasher = Sha256::new();
+    let mut stats = Stats::default();
+    let mut buf = Vec::new();
+    let mut chunk = vec![0; READ_SIZE];
+    let (mut lit, mut pos) = (0, 0);
+    let mut weak: Option<Adler32> = None;
+    let mut eof = false;
+    loop {
+        while !eof && buf.len() < pos + bs + 1 {
+            let n = src.read(&mut chunk).await?;
+            eof = n == 0;
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        if buf.len() < pos + bs {
+            break;
+        }
+        let window = &buf[pos..pos + bs];
+        let w = weak.get_or_insert_with(|| Adler32::new(window));
+        if let Some(i) = sig.find(w.hash(), window) {
+            write_literal(&mut out, &buf[lit..pos], &mut hasher, &mut stats).await?;
+            out.write_u8(b'C').await?;
+            out.write_u64(i).await?;
+            hasher.update(window);
+            stats.matched += bs as u64;
+            pos += bs;
+            lit = pos;
+            weak = None;
+        } else if buf.len() == pos + bs {
+            break;
+        } else {
+            w.roll(buf[pos], buf[pos + bs]);
+            pos += 1;
+            if pos - lit >= MAX_LITERAL {
+                write_literal(&mut out, &buf[lit..pos], &mut hasher, &mut stats).await?;
+                lit = pos;
+            }
+        }
+        if lit >= MAX_LITERAL {
+            buf.drain(..lit);
+            pos -= lit;
+            lit = 0;
+        }
+    }
+    write_literal(&mut out, &buf[lit..], &mut hasher, &mut stats).await?;
+    out.write_u8(b'E').await?;
+    out.write_all(&hasher.finalize()).await?;
+    out.flush().await?;
+    Ok(stats)
+}
+
+async fn signature(user: &User, path: &U8Path, block_size: usize) -> Result<Option<Signature>> {
+    let script = helper_script(&["sig", &block_size.to_string(), path.as_str()]);
+    let output = user.exec(Script::sh(&script)).await?;
+    let sig = Signature::parse(&String::from_utf8_lossy(&output.stdout));
+    if sig.is_none() {
+        debug!(
+            "delta helper unavailable: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(sig)
+}
+
+async fn transfer(
+    src: &User,
+    src_path: &U8Path,
+    dst: &User,
+    dst_path: &U8Path,
+    delta_path: &U8Path,
+    sig: &Signature,
+    file: Option<&FileGuard<'_>>,
+) -> Result<()> {
+    let from = src.open(src_path, OpenFlags::READ).await?;
+    let from: Box<dyn AsyncRead + Unpin + Send + '_> = match file {
+        Some(file) => Box::new(file.reader(from)),
+        None => Box::new(from),
+    };
+    let mut to = dst
+        .open_from(
+            delta_path,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            FileAttributes::default(),
+            src,
+        )
+        .await?;
+    let stats = generate(sig, from, &mut to).await?;
+    to.shutdown().await?;
+    debug!(
+        "delta {dst_path}: {} bytes matched, {} bytes literal",
+        stats.matched, stats.literal
+    );
+    let script = helper_script(&[
+        "patch",
+        &sig.block_size.to_string(),
+        dst_path.as_str(),
+        delta_path.as_str(),
+    ]);
+    let output = dst.exec(Script::sh(&script)).await?;
+    if !String::from_utf8_lossy(&output.stdout).contains("dv-delta ok") {
+        bail!(
+            "delta patch {dst_path} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+    }
+    Ok(())
+}
+
+/// Try to update an existing `dst_path` from `src_path` with a delta transfer.
+///
+/// Returns `false` without touching anything if `dst_path` is missing or smaller than
+/// `min_size`, or if the destination can't run the helper; the caller should do a full copy.
+pub async fn try_delta(
+    src: &User,
+    src_path: &U8Path,
+    dst: &User,
+    dst_path: &U8Path,
+    min_size: u64,
+    file: Option<&FileGuard<'_>>,
+) -> Result<bool> {
+    let (dst_path, Some(attr)) = dst.file_attributes(dst_path).await? else {
+        return Ok(false);
+    };
+    let size = attr.size.unwrap_or_default();
+    if attr.is_dir() || size < min_size {
+        return Ok(false);
+    }
+    let Some(sig) = signature(dst, &dst_path, block_size(size)).await? else {
+        return Ok(false);
+    };
+    let delta_path = U8PathBuf::from(format!("{dst_path}.dvdelta"));
+    let res = transfer(src, src_path, dst, &dst_path, &delta_path, &sig, file).await;
+    if res.is_err()
+        && let Err(e) = dst.rm(&delta_path).await
+    {
+        warn!("failed to remove {delta_path}: {e}");
+    }
+    res.map(|_| true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(old: &[u8], bs: usize, delta: &[u8]) -> Vec<u8> {
+        let mut new = Vec::new();
+        let mut d = delta;
+        loop {
+            let (op, rest) = d.split_first().unwrap();
+            match op {
+                b'C' => {
+                    let i = u64::from_be_bytes(rest[..8].try_into().unwrap()) as usize;
+                    new.extend_from_slice(&old[i * bs..(i + 1) * bs]);
+                    d = &rest[8..];
+                }
+                b'L' => {
+                    let n = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+                    new.extend_from_slice(&rest[4..4 + n]);
+                    d = &rest[4 + n..];
+                }
+                b'E' => {
+                    assert_eq!(&rest[..32], Sha256::digest(&new).as_slice());
+                    return new;
+                }
+                _ => panic!("bad op {op}"),
+            }
+        }
+    }
+
+    #[test]
+    fn adler32() {
+        assert_eq!(Adler32::new(b"Wikipedia").hash(), 0x11E60398);
+        let data = (0..1000u32)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut w = Adler32::new(&data[..100]);
+        for i in 0..900 {
+            w.roll(data[i], data[i + 100]);
+            assert_eq!(w.hash(), Adler32::new(&data[i + 1..i + 101]).hash());
+        }
+    }
+
+    #[tokio::test]
+    async fn delta() {
+        let bs = 64;
+        let old = (0..4096u32)
+            .map(|i| (i * 31 % 253) as u8)
+            .collect::<Vec<_>>();
+        let mut new = old.clone();
+        new.splice(100..100, b"inserted".iter().copied());
+        new[2000..2010].fill(0);
+        new.truncate(3000);
+        new.extend_from_slice(&old[..200]);
+        let sig = Signature::compute(&old, bs);
+        let mut out = Vec::new();
+        let stats = generate(&sig, new.as_slice(), &mut out).await.unwrap();
+        assert_eq!(apply(&old, bs, &out), new);
+        assert_eq!(stats.matched + stats.literal, new.len() as u64);
+        assert!(stats.literal < 500, "{stats:?}");
+    }
+
+    #[test]
+    fn parse_signature() {
+        let out = format!(
+            "dv-delta 4\n{} 4 {}\n1 2 {}\n",
+            Adler32::new(b"abcd").hash(),
+            "00".repeat(32),
+            "ff".repeat(32)
+        );
+        let sig = Signature::parse(&out).unwrap();
+        assert_eq!(sig.block_size, 4);
+        assert_eq!(sig.blocks.len(), 1);
+        assert!(Signature::parse("python3: not found").is_none());
+    }
+}
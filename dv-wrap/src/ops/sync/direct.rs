@@ -0,0 +1,91 @@
+//! Direct transfers between two SSH users.
+//!
+//! The source host runs `ssh` to the destination and pipes the file through it, so the content
+//! never passes the controller. It needs the source host to reach and authenticate to the
+//! destination on its own, e.g. with a key in its `~/.ssh`; otherwise the caller relays.
+use tracing::{debug, info};
+
+use super::super::dev::*;
+use super::shell_quote;
+
+/// Exit code of `ssh` when it can't connect or authenticate.
+const SSH_FAILED: i32 = 255;
+
+/// The `user@host` and port other hosts reach `user` at, `None` for non-SSH users.
+fn target(user: &User) -> Option<(&str, &str)> {
+    let addr = user.vars.get("ssh_addr")?;
+    let port = user.vars.get("ssh_port").map_or("22", String::as_str);
+    Some((addr, port))
+}
+
+/// Whether both users can be tried for a direct transfer.
+pub fn supported(src: &User, dst: &User) -> bool {
+    target(src).is_some() && target(dst).is_some()
+}
+
+fn command(src_path: &U8Path, (addr, port): (&str, &str), dst_path: &U8Path) -> String {
+    let tmp = format!("{dst_path}.dvtmp");
+    let remote = format!(
+        "mkdir -p {} && cat > {} && mv -f {} {}",
+        shell_quote(dst_path.parent().map_or(".", |p| p.as_str())),
+        shell_quote(&tmp),
+        shell_quote(&tmp),
+        shell_quote(dst_path.as_str()),
+    );
+    format!(
+        "ssh -o BatchMode=yes -o ConnectTimeout=5 -p {} {} {} < {}",
+        shell_quote(port),
+        shell_quote(addr),
+        shell_quote(&remote),
+        shell_quote(src_path.as_str()),
+    )
+}
+
+/// Let the source host push `src_path` to the destination.
+///
+/// Returns `Ok(false)` if the source host can't connect to the destination.
+pub async fn try_direct(
+    src: &User,
+    src_path: &U8Path,
+    dst: &User,
+    dst_path: &U8Path,
+) -> Result<bool> {
+    let Some(to) = target(dst) else {
+        return Ok(false);
+    };
+    let cmd = command(src_path, to, dst_path);
+    debug!("direct transfer: {cmd}");
+    let output = src.exec(Script::sh(&cmd)).await?;
+    match output.code {
+        0 => {
+            info!("pushed {src_path} directly to {}:{dst_path}", to.0);
+            Ok(true)
+        }
+        SSH_FAILED => {
+            debug!(
+                "{} unreachable from source: {}",
+                to.0,
+                String::from_utf8_lossy(&output.stdout)
+            );
+            Ok(false)
+        }
+        code => bail!(
+            "direct transfer {src_path} -> {dst_path} exited with {code}: {}",
+            String::from_utf8_lossy(&output.stdout)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        let cmd = command("/a/it's".into(), ("me@h", "2222"), "/b/c d".into());
+        assert_eq!(
+            cmd,
+            r#"ssh -o BatchMode=yes -o ConnectTimeout=5 -p '2222' 'me@h' 'mkdir -p '\''/b'\'' && cat > '\''/b/c d.dvtmp'\'' && mv -f '\''/b/c d.dvtmp'\'' '\''/b/c d'\''' < '/a/it'\''s'"#
+        );
+    }
+}
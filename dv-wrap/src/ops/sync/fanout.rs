@@ -0,0 +1,190 @@
+//! Sync one source to many destinations.
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+use futures::{StreamExt, stream};
+
+use super::super::dev::*;
+use super::{Entry, Opt, Policy, Source, SyncContext, host};
+
+/// Default number of destinations [`FanOut`] scans and executes at the same time.
+pub const DEFAULT_PARALLELISM: usize = 4;
+
+/// The entries of one destination, or why it couldn't be scanned.
+pub struct FanOutPlan<'a> {
+    pub duid: &'a str,
+    pub entries: Result<Vec<Entry>>,
+}
+
+/// The results of one destination, in the same order as the entries of its plan.
+pub struct FanOutResult<'a> {
+    pub duid: &'a str,
+    pub results: Result<Vec<Result<()>>>,
+}
+
+pub struct FanOut<'a> {
+    ctx: &'a Context,
+    suid: &'a str,
+    syncs: Vec<SyncContext<'a>>,
+    parallelism: usize,
+}
+
+fn describe(offered: Opt) -> &'static str {
+    if offered == Opt::OVERWRITE | Opt::UPDATE {
+        "changed on both sides"
+    } else if offered == Opt::OVERWRITE {
+        "source changed"
+    } else if offered == Opt::UPDATE {
+        "destination changed"
+    } else if offered.contains(Opt::UPLOAD) {
+        "only in source"
+    } else {
+        "only in destination"
+    }
+}
+
+impl<'a> FanOut<'a> {
+    pub fn new(
+        ctx: &'a Context,
+        suid: &'a str,
+        duids: impl IntoIterator<Item = &'a str>,
+        opts: &'a [Opt],
+    ) -> Self {
+        Self {
+            ctx,
+            suid,
+            syncs: duids
+                .into_iter()
+                .map(|duid| SyncContext::new(ctx, suid, duid, opts))
+                .collect(),
+            parallelism: DEFAULT_PARALLELISM,
+        }
+    }
+
+    /// Set the maximum number of destinations scanned or executed at the same time.
+    pub fn set_parallelism(&mut self, limit: usize) {
+        self.parallelism = limit.max(1);
+    }
+
+    /// The contexts of all destinations, e.g. to set transfer options or policies.
+    pub fn syncs_mut(&mut self) -> &mut [SyncContext<'a>] {
+        &mut self.syncs
+    }
+
+    /// Walk the source once and scan every destination against it.
+    ///
+    /// Changes that neither the preset opts nor the policies decide are merged over all
+    /// destinations and confirmed once, choosing the policy that resolves all of them.
+    pub async fn scan(
+        &self,
+        src_path: impl AsRef<str>,
+        dst_path: impl AsRef<str>,
+    ) -> Result<Vec<FanOutPlan<'a>>> {
+        let src = self.ctx.get_user(self.suid)?;
+        let source = Source::walk(src, src_path.as_ref()).await?;
+        let dst_path = dst_path.as_ref();
+        let scans = stream::iter(&self.syncs)
+            .map(|sync| {
+                let source = &source;
+                async move {
+                    let deferred = Mutex::default();
+                    let entries = sync.scan_from(source, dst_path, Some(&deferred)).await;
+                    let deferred = deferred.into_inner().expect("deferred entries poisoned");
+                    (entries, deferred)
+                }
+            })
+            .buffered(self.parallelism)
+            .collect::<Vec<_>>()
+            .await;
+        let policy = if scans.iter().any(|(_, deferred)| !deferred.is_empty()) {
+            self.confirm(&source, &scans).await?
+        } else {
+            None
+        };
+        let src_host = host(src, self.suid);
+        let mut plans = Vec::with_capacity(self.syncs.len());
+        for (sync, (entries, deferred)) in self.syncs.iter().zip(scans) {
+            let entries = entries.and_then(|mut entries| {
+                // without a policy the undecided entries are skipped
+                let Some(policy) = policy else {
+                    return Ok(entries);
+                };
+                let dst_host = host(self.ctx.get_user(sync.duid)?, sync.duid);
+                for mut entry in deferred {
+                    if policy.apply(&mut entry, src_host, dst_host) && !entry.opt.is_empty() {
+                        entries.push(entry);
+                    }
+                }
+                Ok(entries)
+            });
+            plans.push(FanOutPlan {
+                duid: sync.duid,
+                entries,
+            });
+        }
+        Ok(plans)
+    }
+
+    /// Show the merged undecided changes and ask for the policy resolving them, `None` skips
+    /// them.
+    async fn confirm(
+        &self,
+        source: &Source,
+        scans: &[(Result<Vec<Entry>>, Vec<Entry>)],
+    ) -> Result<Option<Policy>> {
+        let mut groups = BTreeMap::<_, Vec<&str>>::new();
+        for (sync, (_, deferred)) in self.syncs.iter().zip(scans) {
+            for entry in deferred {
+                let rel = entry
+                    .src
+                    .strip_prefix(&source.path)
+                    .ok()
+                    .filter(|rel| !rel.as_str().is_empty())
+                    .unwrap_or(&entry.src);
+                groups
+                    .entry((rel.as_str(), entry.opt.bits()))
+                    .or_default()
+                    .push(sync.duid);
+            }
+        }
+        let mut msg = format!("undecided changes from {}:{}\n", self.suid, source.path);
+        for ((rel, offered), duids) in &groups {
+            let offered = describe(Opt::from_bits_truncate(*offered));
+            writeln!(&mut msg, "  {rel} ({offered}) on {}", duids.join(", ")).unwrap();
+        }
+        let opts = [
+            "s/source wins",
+            "d/destination wins",
+            "t/newest wins",
+            "b/keep both",
+            "n/skip",
+            "a/abort",
+        ];
+        match self.ctx.interactor.confirm(msg, &opts).await? {
+            0 => Ok(Some(Policy::Source)),
+            1 => Ok(Some(Policy::Dest)),
+            2 => Ok(Some(Policy::Newest)),
+            3 => Ok(Some(Policy::KeepBoth)),
+            4 => Ok(None),
+            _ => bail!("sync from {}:{} aborted", self.suid, source.path),
+        }
+    }
+
+    /// Execute the plans of all destinations, at most [`set_parallelism`](Self::set_parallelism)
+    /// at the same time.
+    pub async fn execute(&self, plans: &[FanOutPlan<'_>]) -> Vec<FanOutResult<'a>> {
+        stream::iter(self.syncs.iter().zip(plans))
+            .map(|(sync, plan)| async move {
+                let results = match &plan.entries {
+                    Ok(entries) => sync.execute_each(entries).await,
+                    Err(e) => Err(anyhow::anyhow!("scan failed: {e}")),
+                };
+                FanOutResult {
+                    duid: sync.duid,
+                    results,
+                }
+            })
+            .buffered(self.parallelism)
+            .collect()
+            .await
+    }
+}
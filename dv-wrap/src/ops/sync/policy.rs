@@ -0,0 +1,156 @@
+//! Unattended resolution of changes the preset [`Opt`]s don't cover.
+use globset::{Glob, GlobMatcher};
+
+use super::{Entry, Opt};
+use crate::dev::*;
+
+/// How a change is resolved instead of asking the interactor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Policy {
+    /// The side with the newer mtime wins, a file that exists on one side only is copied.
+    Newest,
+    /// The destination is made to match the source.
+    Source,
+    /// The source is made to match the destination.
+    Dest,
+    /// Like [`Policy::Newest`], but the losing file is renamed with a host suffix first.
+    KeepBoth,
+    /// Fail the scan.
+    Fail,
+}
+
+impl Policy {
+    /// Pick one of `offered`, `None` means the change must not be resolved.
+    pub fn resolve(
+        self,
+        offered: Opt,
+        src_mtime: Option<i64>,
+        dst_mtime: Option<i64>,
+    ) -> Option<Opt> {
+        let (src_wins, dst_wins) = if offered.intersects(Opt::OVERWRITE | Opt::UPDATE) {
+            (Opt::OVERWRITE, Opt::UPDATE)
+        } else if offered.intersects(Opt::UPLOAD | Opt::DELETESRC) {
+            (Opt::UPLOAD, Opt::DELETESRC)
+        } else {
+            (Opt::DELETEDST, Opt::DOWNLOAD)
+        };
+        match self {
+            Policy::Source => Some(src_wins),
+            Policy::Dest => Some(dst_wins),
+            Policy::Newest | Policy::KeepBoth => Some(match (src_mtime, dst_mtime) {
+                (Some(s), Some(d)) if s < d => dst_wins,
+                (Some(_), _) => src_wins,
+                (None, _) => dst_wins,
+            }),
+            Policy::Fail => None,
+        }
+    }
+    /// Decide `entry` whose `opt` holds the offered opts, returns `false` for [`Policy::Fail`].
+    ///
+    /// The hosts name the backup of the losing side under [`Policy::KeepBoth`].
+    pub fn apply(self, entry: &mut Entry, src_host: &str, dst_host: &str) -> bool {
+        let Some(opt) = self.resolve(entry.opt, entry.src_attr, entry.dst_attr) else {
+            return false;
+        };
+        entry.opt = opt;
+        entry.policy = Some(self);
+        if self == Policy::KeepBoth {
+            entry.backup = match opt {
+                Opt::OVERWRITE => Some(keep_path(&entry.dst, dst_host)),
+                Opt::UPDATE => Some(keep_path(&entry.src, src_host)),
+                _ => None,
+            };
+        }
+        true
+    }
+}
+
+/// Policies of one sync, the first matching path pattern takes precedence over the default.
+#[derive(Debug, Default)]
+pub struct Policies {
+    pub default: Option<Policy>,
+    rules: Vec<(GlobMatcher, Policy)>,
+}
+
+impl Policies {
+    pub fn add(&mut self, pattern: &str, policy: Policy) -> Result<()> {
+        self.rules
+            .push((Glob::new(pattern)?.compile_matcher(), policy));
+        Ok(())
+    }
+    /// Find the policy for a change between `sp` and `dp`.
+    pub fn get(&self, sp: &U8Path, dp: &U8Path) -> Option<Policy> {
+        self.rules
+            .iter()
+            .find(|(glob, _)| glob.is_match(sp) || glob.is_match(dp))
+            .map(|(_, policy)| *policy)
+            .or(self.default)
+    }
+}
+
+/// The name the losing file of [`Policy::KeepBoth`] is renamed to, e.g. `a.host.txt`.
+pub fn keep_path(path: &U8Path, host: &str) -> U8PathBuf {
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => path.with_file_name(format!("{stem}.{host}.{ext}")),
+        _ => format!("{path}.{host}").into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve() {
+        let both = Opt::OVERWRITE | Opt::UPDATE;
+        assert_eq!(
+            Policy::Newest.resolve(both, Some(1), Some(2)),
+            Some(Opt::UPDATE)
+        );
+        assert_eq!(
+            Policy::Newest.resolve(both, Some(2), Some(1)),
+            Some(Opt::OVERWRITE)
+        );
+        assert_eq!(
+            Policy::Source.resolve(Opt::UPDATE, Some(1), Some(2)),
+            Some(Opt::OVERWRITE)
+        );
+        assert_eq!(
+            Policy::Source.resolve(Opt::DOWNLOAD | Opt::DELETEDST, None, Some(1)),
+            Some(Opt::DELETEDST)
+        );
+        assert_eq!(
+            Policy::Dest.resolve(Opt::UPLOAD | Opt::DELETESRC, Some(1), None),
+            Some(Opt::DELETESRC)
+        );
+        assert_eq!(
+            Policy::KeepBoth.resolve(Opt::DOWNLOAD | Opt::DELETEDST, None, Some(1)),
+            Some(Opt::DOWNLOAD)
+        );
+        assert_eq!(Policy::Fail.resolve(both, Some(1), Some(2)), None);
+        assert_eq!("keep-both".parse::<Policy>().unwrap(), Policy::KeepBoth);
+    }
+
+    #[test]
+    fn rules() {
+        let mut policies = Policies {
+            default: Some(Policy::Fail),
+            ..Default::default()
+        };
+        policies.add("*.log", Policy::Source).unwrap();
+        assert_eq!(
+            policies.get("/a/b.log".into(), "/c/b.log".into()),
+            Some(Policy::Source)
+        );
+        assert_eq!(
+            policies.get("/a/b".into(), "/c/b".into()),
+            Some(Policy::Fail)
+        );
+        assert_eq!(
+            keep_path("/a/b.txt".into(), "h"),
+            U8PathBuf::from("/a/b.h.txt")
+        );
+        assert_eq!(keep_path("/a/b".into(), "h"), U8PathBuf::from("/a/b.h"));
+    }
+}
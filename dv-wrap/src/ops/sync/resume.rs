@@ -0,0 +1,173 @@
+//! Resumable copies.
+//!
+//! Large files are copied into `<dst>.dvpart` first. Every [`CHECKPOINT`] bytes the cache records
+//! the source identity (size and mtime) as version, with the copied length as size and the sha256
+//! of that prefix as hash, under the part path. A later copy of the same source verifies both the
+//! source prefix and the part file against the recorded hash and continues writing the part file
+//! from that offset.
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, info};
+
+use super::super::dev::*;
+use super::COPY_BUF_SIZE;
+use crate::{MultiDB, OpKind, Record, progress::FileGuard};
+
+/// Files smaller than this are copied directly.
+pub const MIN_SIZE: u64 = 1024 * 1024;
+/// How often the copied prefix is recorded.
+const CHECKPOINT: u64 = 8 * 1024 * 1024;
+
+pub fn part_path(path: &U8Path) -> U8PathBuf {
+    format!("{path}.dvpart").into()
+}
+
+/// Hash the first `len` bytes of `file`, `None` if it is shorter.
+async fn hash_prefix(file: &mut BoxedFile, len: u64) -> Result<Option<Sha256>> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; COPY_BUF_SIZE];
+    let mut left = len;
+    while left > 0 {
+        let n = file
+            .read(&mut buf[..left.min(COPY_BUF_SIZE as u64) as usize])
+            .await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        hasher.update(&buf[..n]);
+        left -= n as u64;
+    }
+    Ok(Some(hasher))
+}
+
+/// Whether `hasher` exists and has the hex digest `hash`.
+fn matches(hasher: &Option<Sha256>, hash: &str) -> bool {
+    hasher
+        .as_ref()
+        .is_some_and(|h| format!("{:x}", h.clone().finalize()) == hash)
+}
+
+/// Check the recorded progress of `part` and return the verified offset with the hash state of
+/// the source prefix, leaving `from` positioned at that offset.
+async fn verified_offset(
+    db: &MultiDB,
+    uid: &str,
+    ident: &str,
+    dst: &User,
+    part: &U8Path,
+    from: &mut BoxedFile,
+) -> Result<(u64, Sha256)> {
+    let fresh = Ok((0, Sha256::new()));
+    let Some(record) = db.get_record(uid, part.as_str()).await? else {
+        return fresh;
+    };
+    let (Some(done), Some(hash)) = (
+        record.size,
+        record
+            .hash
+            .as_deref()
+            .and_then(|h| h.strip_prefix("sha256:")),
+    ) else {
+        return fresh;
+    };
+    let part_size = dst.file_attributes(part).await?.1.and_then(|a| a.size);
+    if record.version != ident || part_size.is_none_or(|s| s < done) {
+        debug!("stale part {part}, start over");
+        return fresh;
+    }
+    let source = hash_prefix(from, done).await?;
+    // the part may have been damaged or replaced since it was recorded
+    let written = match matches(&source, hash) {
+        true => hash_prefix(&mut dst.open(part, OpenFlags::READ).await?, done).await?,
+        false => None,
+    };
+    match source {
+        Some(source) if matches(&written, hash) => {
+            info!("resume {part} from {done}");
+            Ok((done, source))
+        }
+        _ => {
+            debug!("{part} doesn't match its record, start over");
+            from.seek(std::io::SeekFrom::Start(0)).await?;
+            fresh
+        }
+    }
+}
+
+/// Copy `src_path` to `dst_path` through a resumable part file.
+///
+/// `ident` identifies the source content, e.g. its size and mtime; `uid` is the cache device
+/// the progress is recorded under.
+#[allow(clippy::too_many_arguments)]
+pub async fn copy(
+    db: &MultiDB,
+    uid: &str,
+    ident: &str,
+    src: &User,
+    src_path: &U8Path,
+    dst: &User,
+    dst_path: &U8Path,
+    file: Option<&FileGuard<'_>>,
+) -> Result<()> {
+    let part = part_path(dst_path);
+    let mut from = src.open(src_path, OpenFlags::READ).await?;
+    let (mut offset, mut hasher) = verified_offset(db, uid, ident, dst, &part, &mut from).await?;
+    let mut to = if offset > 0 {
+        let mut to = dst
+            .open_from(&part, OpenFlags::WRITE, FileAttributes::default(), src)
+            .await?;
+        to.seek(std::io::SeekFrom::Start(offset)).await?;
+        to
+    } else {
+        // the part is renamed over the destination, so it takes the mode of the existing file
+        let attr = match dst.file_attributes(dst_path).await?.1 {
+            Some(attr) => FileAttributes {
+                permissions: attr.permissions,
+                ..Default::default()
+            },
+            None => FileAttributes::default(),
+        };
+        if dst.exist(&part).await? {
+            dst.rm(&part).await?;
+        }
+        dst.open_from(
+            &part,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            attr,
+            src,
+        )
+        .await?
+    };
+    if let Some(file) = file {
+        file.add(offset);
+    }
+    let mut buf = vec![0; COPY_BUF_SIZE];
+    let mut checkpoint = offset + CHECKPOINT;
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        to.write_all(&buf[..n]).await?;
+        hasher.update(&buf[..n]);
+        offset += n as u64;
+        if let Some(file) = file {
+            file.add(n as u64);
+            file.throttle(n as u64).await;
+        }
+        if offset >= checkpoint {
+            to.flush().await?;
+            let record = Record {
+                size: Some(offset),
+                hash: Some(format!("sha256:{:x}", hasher.clone().finalize())),
+                ..Record::new(OpKind::Resume, ident, "")
+            };
+            db.set_record(uid, part.as_str(), &record).await?;
+            checkpoint = offset + CHECKPOINT;
+        }
+    }
+    to.shutdown().await?;
+    dst.rename(&part, dst_path).await?;
+    db.del(uid, part.as_str()).await?;
+    Ok(())
+}
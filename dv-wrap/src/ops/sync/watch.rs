@@ -0,0 +1,174 @@
+//! Continuous sync driven by filesystem events.
+//!
+//! Both sides are watched when their user supports it; a side that can't be watched is polled
+//! with a full rescan instead. Changed paths are collected until no new event arrived for the
+//! debounce window, then only those paths are scanned and applied with the configured [`Opt`]s.
+use std::{collections::BTreeSet, time::Duration};
+
+use tracing::{debug, info, warn};
+
+use super::{Entry, Opt, SyncContext};
+use crate::dev::*;
+
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+enum Event {
+    Src(Option<U8PathBuf>),
+    Dst(Option<U8PathBuf>),
+    Poll,
+    Flush,
+}
+
+async fn next_event(events: &mut Option<WatchEvents>) -> Option<U8PathBuf> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Insert the path of `changed` relative to `root`, ignoring our own temporary files.
+fn insert(paths: &mut BTreeSet<U8PathBuf>, root: &U8Path, changed: &U8Path) {
+    let Ok(rel) = changed.strip_prefix(root) else {
+        return;
+    };
+    if let Some(ext) = rel.extension()
+        && ["dvpart", "dvdelta", "dvtmp"].contains(&ext)
+    {
+        return;
+    }
+    paths.insert(rel.to_owned());
+}
+
+fn describe(opt: Opt) -> &'static str {
+    match opt {
+        Opt::OVERWRITE => "overwrite",
+        Opt::UPDATE => "update",
+        Opt::UPLOAD => "upload",
+        Opt::DOWNLOAD => "download",
+        Opt::DELETEDST | Opt::DELETESRC => "delete",
+        _ => "skip",
+    }
+}
+
+impl SyncContext<'_> {
+    /// Keep `src_path` and `dst_path` in sync until the returned future is dropped.
+    ///
+    /// The paths are synced once up front, afterwards only changed paths are rescanned. Every
+    /// applied change is reported through the interactor.
+    pub async fn watch(&self, src_path: impl AsRef<str>, dst_path: impl AsRef<str>) -> Result<()> {
+        let src = self.ctx.get_user(self.suid)?;
+        let dst = self.ctx.get_user(self.duid)?;
+        let (sp, sa) = src.file_attributes(src_path.as_ref().into()).await?;
+        let (dp, da) = dst.file_attributes(dst_path.as_ref().into()).await?;
+        // subscribe first so changes made during the initial sync are seen afterwards
+        let mut src_events = match sa {
+            Some(_) => src.watch(&sp).await?,
+            None => None,
+        };
+        let mut dst_events = match da {
+            Some(_) => dst.watch(&dp).await?,
+            None => None,
+        };
+        self.sync_changed(&sp, &dp, [U8PathBuf::new()].into())
+            .await?;
+        let mut changed = BTreeSet::new();
+        // a side the initial sync created is watched from now on, rescan what it missed
+        if sa.is_none() && src.exist(&sp).await? {
+            src_events = src.watch(&sp).await?;
+            changed.insert(U8PathBuf::new());
+        }
+        if da.is_none() && dst.exist(&dp).await? {
+            dst_events = dst.watch(&dp).await?;
+            changed.insert(U8PathBuf::new());
+        }
+        let mut poll = tokio::time::interval(self.poll_interval);
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        info!("watch {}:{} -> {}:{}", self.suid, sp, self.duid, dp);
+        loop {
+            let polling = src_events.is_none() || dst_events.is_none();
+            let event = tokio::select! {
+                path = next_event(&mut src_events) => Event::Src(path),
+                path = next_event(&mut dst_events) => Event::Dst(path),
+                _ = poll.tick(), if polling => Event::Poll,
+                _ = tokio::time::sleep(self.debounce), if !changed.is_empty() => Event::Flush,
+            };
+            match event {
+                Event::Src(Some(path)) => insert(&mut changed, &sp, &path),
+                Event::Dst(Some(path)) => insert(&mut changed, &dp, &path),
+                Event::Src(None) => {
+                    warn!("stop watching {}:{}, fall back to polling", self.suid, sp);
+                    src_events = None;
+                }
+                Event::Dst(None) => {
+                    warn!("stop watching {}:{}, fall back to polling", self.duid, dp);
+                    dst_events = None;
+                }
+                Event::Poll => {
+                    changed.insert(U8PathBuf::new());
+                }
+                Event::Flush => {
+                    self.sync_changed(&sp, &dp, std::mem::take(&mut changed))
+                        .await?;
+                }
+            }
+        }
+    }
+    /// Scan and apply the `changed` paths relative to both roots.
+    async fn sync_changed(
+        &self,
+        sp: &U8Path,
+        dp: &U8Path,
+        changed: BTreeSet<U8PathBuf>,
+    ) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut last: Option<&U8Path> = None;
+        for rel in &changed {
+            // a scan of a directory already covers everything below it
+            if last.is_some_and(|l| rel.starts_with(l)) {
+                continue;
+            }
+            last = Some(rel);
+            let (sp, dp) = if rel.as_str().is_empty() {
+                (sp.to_owned(), dp.to_owned())
+            } else {
+                (sp.join(rel), dp.join(rel))
+            };
+            match self.scan(&sp, &dp).await {
+                Ok(e) => entries.extend(e),
+                Err(e) => debug!("skip {rel}: {e}"),
+            }
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.report(&entries, self.execute_each(&entries).await?)
+            .await;
+        Ok(())
+    }
+    async fn report(&self, entries: &[Entry], results: Vec<Result<()>>) {
+        for (entry, res) in entries.iter().zip(results) {
+            let msg = match res {
+                Ok(()) => {
+                    let mut msg = format!(
+                        "{} {}:{} -> {}:{}",
+                        describe(entry.opt),
+                        self.suid,
+                        entry.src,
+                        self.duid,
+                        entry.dst
+                    );
+                    if let Some(policy) = entry.policy {
+                        msg.push_str(&format!(" ({policy})"));
+                    }
+                    msg
+                }
+                Err(e) => format!(
+                    "sync {}:{} -> {}:{} failed: {e}",
+                    self.suid, entry.src, self.duid, entry.dst
+                ),
+            };
+            self.ctx.interactor.log(msg).await;
+        }
+    }
+}
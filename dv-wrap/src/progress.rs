@@ -0,0 +1,252 @@
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{
+    interactor::DynInteractor,
+    throttle::{Limiter, Pacer, RateLimit},
+};
+
+/// How often [`Tracker::run`] reports progress to the interactor.
+const REPORT_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Default)]
+pub struct FileProgress {
+    pub path: String,
+    pub done: u64,
+    pub total: Option<u64>,
+}
+
+/// A snapshot of a running operation.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub title: String,
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    /// Bytes per second since the operation started.
+    pub rate: f64,
+    /// Files being transferred right now.
+    pub active: Vec<FileProgress>,
+    pub finished: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    files_done: usize,
+    bytes_done: u64,
+    next: usize,
+    active: BTreeMap<usize, FileProgress>,
+}
+
+#[derive(Debug)]
+pub struct Tracker {
+    title: String,
+    files_total: usize,
+    start: Instant,
+    state: Mutex<State>,
+    /// Shared by all files of the operation.
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl Tracker {
+    pub fn new(title: impl Into<String>, files_total: usize) -> Self {
+        Self {
+            title: title.into(),
+            files_total,
+            start: Instant::now(),
+            state: Mutex::default(),
+            limiter: None,
+        }
+    }
+    /// Limit the bandwidth of all files transferred through this tracker together.
+    pub fn with_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.limiter = limit.map(|l| Arc::new(Limiter::new(l)));
+        self
+    }
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("progress state poisoned")
+    }
+    /// Start tracking a file, it counts as done when the returned guard is dropped.
+    pub fn file(&self, path: impl Into<String>, total: Option<u64>) -> FileGuard<'_> {
+        let mut state = self.state();
+        let id = state.next;
+        state.next += 1;
+        state.active.insert(
+            id,
+            FileProgress {
+                path: path.into(),
+                done: 0,
+                total,
+            },
+        );
+        FileGuard { tracker: self, id }
+    }
+    pub fn snapshot(&self) -> Progress {
+        let state = self.state();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        Progress {
+            title: self.title.clone(),
+            files_done: state.files_done,
+            files_total: self.files_total,
+            bytes_done: state.bytes_done,
+            rate: if elapsed > 0.0 {
+                state.bytes_done as f64 / elapsed
+            } else {
+                0.0
+            },
+            active: state.active.values().cloned().collect(),
+            finished: false,
+        }
+    }
+    /// Drive `fut` to completion while reporting progress to `int`.
+    pub async fn run<F: Future>(&self, int: &DynInteractor, fut: F) -> F::Output {
+        let mut fut = std::pin::pin!(fut);
+        let mut tick = tokio::time::interval(REPORT_INTERVAL);
+        let out = loop {
+            tokio::select! {
+                out = &mut fut => break out,
+                _ = tick.tick() => int.progress(&self.snapshot()).await,
+            }
+        };
+        let mut progress = self.snapshot();
+        progress.finished = true;
+        int.progress(&progress).await;
+        out
+    }
+}
+
+#[derive(Debug)]
+pub struct FileGuard<'a> {
+    tracker: &'a Tracker,
+    id: usize,
+}
+
+impl FileGuard<'_> {
+    pub fn add(&self, n: u64) {
+        let mut state = self.tracker.state();
+        state.bytes_done += n;
+        if let Some(file) = state.active.get_mut(&self.id) {
+            file.done += n;
+        }
+    }
+    /// Forget the bytes counted so far, before the file is transferred again from the start.
+    pub fn reset(&self) {
+        let mut state = self.tracker.state();
+        if let Some(file) = state.active.get_mut(&self.id) {
+            let done = std::mem::take(&mut file.done);
+            state.bytes_done -= done;
+        }
+    }
+    /// Wait until `n` transferred bytes fit into the bandwidth limit of the tracker.
+    pub async fn throttle(&self, n: u64) {
+        if let Some(limiter) = &self.tracker.limiter {
+            limiter.consume(n).await;
+        }
+    }
+    /// Wrap `inner` so everything read from it counts towards this file and is throttled by
+    /// the limit of the tracker.
+    pub fn reader<R>(&self, inner: R) -> ProgressReader<'_, R> {
+        ProgressReader {
+            inner,
+            file: self,
+            pacer: self.tracker.limiter.clone().map(Pacer::new),
+        }
+    }
+}
+
+impl Drop for FileGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.tracker.state();
+        state.active.remove(&self.id);
+        state.files_done += 1;
+    }
+}
+
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    file: &'a FileGuard<'a>,
+    pacer: Option<Pacer>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if let Some(pacer) = &mut this.pacer {
+            ready!(pacer.poll_ready(cx));
+        }
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let n = (buf.filled().len() - before) as u64;
+            this.file.add(n);
+            if let Some(pacer) = &mut this.pacer {
+                pacer.charge(n);
+            }
+        }
+        res
+    }
+}
+
+/// Format a byte count with a binary unit, e.g. `1.5 MiB`.
+pub fn human_bytes(n: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut n = n;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{n:.0} {}", UNITS[unit])
+    } else {
+        format!("{n:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn track() {
+        let tracker = Tracker::new("test", 2);
+        {
+            let file = tracker.file("f0", Some(5));
+            let mut content = String::new();
+            file.reader(b"hello".as_slice())
+                .read_to_string(&mut content)
+                .await
+                .unwrap();
+            let p = tracker.snapshot();
+            assert_eq!(p.active.len(), 1);
+            assert_eq!(p.active[0].done, 5);
+            assert_eq!(p.files_done, 0);
+            // a retry from the start doesn't count the first attempt
+            file.reset();
+            file.add(5);
+            assert_eq!(tracker.snapshot().bytes_done, 5);
+        }
+        let p = tracker.snapshot();
+        assert_eq!((p.files_done, p.files_total, p.bytes_done), (1, 2, 5));
+        assert!(p.active.is_empty());
+    }
+
+    #[test]
+    fn human() {
+        assert_eq!(human_bytes(512.0), "512 B");
+        assert_eq!(human_bytes(1536.0), "1.5 KiB");
+        assert_eq!(human_bytes(3.0 * 1024.0 * 1024.0), "3.0 MiB");
+    }
+}
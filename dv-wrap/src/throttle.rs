@@ -0,0 +1,263 @@
+//! Bandwidth limiting with a token bucket.
+//!
+//! Transfers charge a [`Limiter`] after each read or write and sleep off any debt before the
+//! next one, so several transfers sharing one limiter stay below its rate together.
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use crate::dev::*;
+
+/// A rate in bytes per second with the number of bytes that may pass at once after idling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub rate: u64,
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// `burst` defaults to one second worth of `rate`.
+    pub fn new(rate: u64, burst: Option<u64>) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            burst: burst.unwrap_or(rate).max(1),
+        }
+    }
+    /// Read the `rate_limit` and `rate_burst` user variables, e.g. `rate_limit = "2M"`.
+    ///
+    /// A `rate_limit` of 0 means no limit.
+    pub fn from_vars(vars: &HashMap<String, String>) -> Result<Option<Self>> {
+        let Some(rate) = vars.get("rate_limit") else {
+            return Ok(None);
+        };
+        let rate = parse_bytes(rate)?;
+        if rate == 0 {
+            return Ok(None);
+        }
+        let burst = vars.get("rate_burst").map(|b| parse_bytes(b)).transpose()?;
+        Ok(Some(Self::new(rate, burst)))
+    }
+}
+
+/// Parse a byte count with an optional binary suffix, e.g. `512`, `64K` or `1.5MiB`.
+pub fn parse_bytes(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let shift = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        _ => anyhow::bail!("invalid byte count {s}"),
+    };
+    let Ok(num) = num.parse::<f64>() else {
+        anyhow::bail!("invalid byte count {s}")
+    };
+    Ok((num * (1u64 << shift) as f64) as u64)
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+pub struct Limiter {
+    limit: RateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+impl Limiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            bucket: Mutex::new(Bucket {
+                tokens: limit.burst as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+    /// Charge `n` bytes, returning how long to wait before the next transfer.
+    fn charge(&self, n: u64) -> Duration {
+        let RateLimit { rate, burst } = self.limit;
+        let mut bucket = self.bucket.lock().expect("bucket poisoned");
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last).as_secs_f64() * rate as f64;
+        bucket.tokens = (bucket.tokens + refill).min(burst as f64) - n as f64;
+        bucket.last = now;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+    /// Charge `n` already transferred bytes and wait until they are paid off.
+    pub async fn consume(&self, n: u64) {
+        let wait = self.charge(n);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Pacing state of one stream, the poll based counterpart of [`Limiter::consume`].
+#[derive(Debug)]
+pub struct Pacer {
+    limiter: Arc<Limiter>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Pacer {
+    pub fn new(limiter: Arc<Limiter>) -> Self {
+        Self {
+            limiter,
+            delay: None,
+        }
+    }
+    /// Wait for the debt of previous transfers.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+    pub fn charge(&mut self, n: u64) {
+        let wait = self.limiter.charge(n);
+        if !wait.is_zero() {
+            self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+}
+
+/// A stream whose reads and writes are limited by a shared [`Limiter`].
+pub struct Throttled<T> {
+    inner: T,
+    pacer: Pacer,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, limiter: Arc<Limiter>) -> Self {
+        Self {
+            inner,
+            pacer: Pacer::new(limiter),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Throttled<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.pacer.poll_ready(cx));
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.pacer.charge((buf.filled().len() - before) as u64);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Throttled<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.pacer.poll_ready(cx));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.pacer.charge(n as u64);
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: AsyncSeek + Unpin> AsyncSeek for Throttled<T> {
+    fn start_seek(mut self: Pin<&mut Self>, position: std::io::SeekFrom) -> std::io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + AsyncSeek + Unpin> FileImpl for Throttled<T> {}
+
+/// Throttle `file` by `limiter` if given.
+pub fn file(file: BoxedFile, limiter: Option<&Arc<Limiter>>) -> BoxedFile {
+    match limiter {
+        Some(limiter) => Box::new(Throttled::new(file, limiter.clone())),
+        None => file,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes() {
+        assert_eq!(parse_bytes("512").unwrap(), 512);
+        assert_eq!(parse_bytes("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_bytes("1.5MiB").unwrap(), 3 * 512 * 1024);
+        assert!(parse_bytes("1X").is_err());
+    }
+
+    #[test]
+    fn vars() {
+        let vars = |rate: &str| HashMap::from([("rate_limit".to_string(), rate.to_string())]);
+        assert_eq!(
+            RateLimit::from_vars(&vars("2K")).unwrap(),
+            Some(RateLimit::new(2048, None))
+        );
+        assert_eq!(RateLimit::from_vars(&vars("0")).unwrap(), None);
+        assert_eq!(RateLimit::from_vars(&HashMap::new()).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn shared() {
+        let limiter = Limiter::new(RateLimit::new(1000, Some(500)));
+        // the burst passes at once, later charges queue up behind the debt
+        let a = limiter.charge(1000).as_secs_f64();
+        let b = limiter.charge(1000).as_secs_f64();
+        assert!((0.49..=0.5).contains(&a), "{a}");
+        assert!((1.49..=1.5).contains(&b), "{b}");
+    }
+
+    #[tokio::test]
+    async fn throttled() {
+        use tokio::io::AsyncReadExt;
+        let limiter = Arc::new(Limiter::new(RateLimit::new(10_000, Some(1000))));
+        let data = vec![0u8; 3000];
+        let start = Instant::now();
+        let mut out = Vec::new();
+        Throttled::new(data.as_slice(), limiter)
+            .read_to_end(&mut out)
+            .await
+            .unwrap();
+        assert_eq!(out.len(), 3000);
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}
@@ -4,11 +4,14 @@ use dv_api::{
     multi::{Config, create_local, create_ssh},
 };
 use os2::Os;
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
 
 use tracing::debug;
 
-use crate::utils::var_replace;
+use crate::{
+    throttle::{self, Limiter, RateLimit},
+    utils::var_replace,
+};
 
 use super::dev::*;
 
@@ -17,6 +20,8 @@ pub struct User {
     pub is_system: bool,
     pub vars: HashMap<String, String>,
     inner: BoxedUser,
+    /// Limits the files of this user, shared by all users of the same device.
+    pub(crate) limiter: Option<Arc<Limiter>>,
 }
 
 impl User {
@@ -24,6 +29,7 @@ impl User {
         let inner = create_local(&mut cfg).await?;
         Ok(Self {
             is_system: cfg.is_system.expect("is_system"),
+            limiter: Self::new_limiter(&cfg.variables)?,
             vars: cfg.variables,
             inner,
         })
@@ -35,10 +41,14 @@ impl User {
         let inner = create_ssh(host, &mut cfg).await?;
         Ok(Self {
             is_system: cfg.is_system.expect("is_system"),
+            limiter: Self::new_limiter(&cfg.variables)?,
             vars: cfg.variables,
             inner,
         })
     }
+    fn new_limiter(vars: &HashMap<String, String>) -> Result<Option<Arc<Limiter>>> {
+        Ok(RateLimit::from_vars(vars)?.map(|l| Arc::new(Limiter::new(l))))
+    }
     fn normalize<'a>(&self, path: impl Into<&'a U8Path>) -> Result<Cow<'a, U8Path>> {
         let path: &'a U8Path = path.into();
         let Some(path) = var_replace(path.as_str(), &self.vars) else {
@@ -94,22 +104,51 @@ impl User {
             bail!("{} not found", path)
         };
         let info = if attr.is_dir() {
-            let files = self.inner.glob_file_meta(&path).await?;
+            let files = self.files(&path).await?;
             CheckInfo::Dir(DirInfo { path, files })
         } else {
             CheckInfo::File(Metadata { path, attr })
         };
         Ok(info)
     }
+    /// List files and subdirectories below `path`.
     pub async fn glob(&self, path: &U8Path) -> Result<Vec<Metadata>> {
         Ok(self.inner.glob_file_meta(path).await?)
     }
+    async fn files(&self, path: &U8Path) -> Result<Vec<Metadata>> {
+        let mut files = self.inner.glob_file_meta(path).await?;
+        files.retain(|m| !m.attr.is_dir());
+        Ok(files)
+    }
     pub async fn rm(&self, path: &U8Path) -> Result<()> {
         let path = self.normalize(path)?;
         debug!("rm:{}", path);
         self.inner.rm(&path).await?;
         Ok(())
     }
+    pub async fn create_dir(&self, path: &U8Path, attr: FileAttributes) -> Result<()> {
+        let path = self.normalize(path)?;
+        debug!("create_dir:{}", path);
+        self.inner.create_dir(&path, attr).await?;
+        Ok(())
+    }
+    pub async fn rmdir(&self, path: &U8Path) -> Result<bool> {
+        let path = self.normalize(path)?;
+        debug!("rmdir:{}", path);
+        Ok(self.inner.rmdir(&path).await?)
+    }
+    pub async fn watch(&self, path: &U8Path) -> Result<Option<WatchEvents>> {
+        let path = self.normalize(path)?;
+        debug!("watch:{}", path);
+        Ok(self.inner.watch(&path).await?)
+    }
+    pub async fn rename(&self, from: &U8Path, to: &U8Path) -> Result<()> {
+        let from = self.normalize(from)?;
+        let to = self.normalize(to)?;
+        debug!("rename:{} -> {}", from, to);
+        self.inner.rename(&from, &to).await?;
+        Ok(())
+    }
     pub async fn check_dir(&self, path: &str) -> Result<DirInfo> {
         let path: &U8Path = path.into();
         let (path, fa) = self.file_attributes(path).await?;
@@ -120,7 +159,7 @@ impl User {
         if !attr.is_dir() {
             bail!("{} not a directory", path);
         }
-        let metadata = self.inner.glob_file_meta(&path).await?;
+        let metadata = self.files(&path).await?;
         Ok(DirInfo {
             path,
             files: metadata,
@@ -132,6 +171,9 @@ impl User {
     pub async fn exec(&self, s: Script<'_, '_>) -> dv_api::Result<Output> {
         self.inner.exec(s).await
     }
+    pub async fn spawn(&self, s: Script<'_, '_>) -> Result<BoxedPty> {
+        Ok(self.inner.spawn(s).await?)
+    }
     pub async fn open<P: AsRef<U8Path>>(&self, path: P, opt: OpenFlags) -> Result<BoxedFile> {
         self.open_with_attr(path, opt, FileAttributes::default())
             .await
@@ -143,6 +185,28 @@ impl User {
         attr: FileAttributes,
     ) -> Result<BoxedFile> {
         let path = self.normalize(path.as_ref())?;
-        Ok(self.inner.open(path.as_ref(), flags, attr).await?)
+        let file = self.inner.open(path.as_ref(), flags, attr).await?;
+        Ok(throttle::file(file, self.limiter.as_ref()))
+    }
+    /// Open `path` to write what is read from `from`.
+    ///
+    /// Reading from `from` already charges a limiter both share, e.g. users of one device, so
+    /// the writes are only charged to a limiter of this user alone.
+    pub async fn open_from<P: AsRef<U8Path>>(
+        &self,
+        path: P,
+        flags: OpenFlags,
+        attr: FileAttributes,
+        from: &User,
+    ) -> Result<BoxedFile> {
+        let path = self.normalize(path.as_ref())?;
+        let file = self.inner.open(path.as_ref(), flags, attr).await?;
+        Ok(throttle::file(file, self.write_limiter(from)))
+    }
+    /// The limiter charged for writing data read from `from`, see [`User::open_from`].
+    pub(crate) fn write_limiter(&self, from: &User) -> Option<&Arc<Limiter>> {
+        self.limiter
+            .as_ref()
+            .filter(|l| !from.limiter.as_ref().is_some_and(|f| Arc::ptr_eq(l, f)))
     }
 }
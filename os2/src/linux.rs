@@ -12,16 +12,26 @@ pub enum Linux {
     #[default]
     #[strum(serialize = "linux")]
     Unknown,
+    #[strum(serialize = "almalinux")]
+    Alma,
     #[strum(serialize = "alpine")]
     Alpine,
     #[strum(serialize = "arch")]
     Arch,
+    /// CentOS Stream.
+    #[strum(serialize = "centos")]
+    CentOs,
     #[strum(serialize = "debian")]
     Debian,
     #[strum(serialize = "fedora")]
     Fedora,
     #[strum(serialize = "manjaro")]
     Manjaro,
+    /// Red Hat Enterprise Linux.
+    #[strum(serialize = "rhel")]
+    Rhel,
+    #[strum(serialize = "rocky")]
+    Rocky,
     #[strum(serialize = "ubuntu")]
     Ubuntu,
 }
@@ -59,6 +69,10 @@ mod tests {
         assert_eq!(Linux::from_str("alpine").unwrap(), Linux::Alpine);
         assert_eq!(Linux::from_str("debian").unwrap(), Linux::Debian);
         assert_eq!(Linux::from_str("ubuntu").unwrap(), Linux::Ubuntu);
+        assert_eq!(Linux::from_str("almalinux").unwrap(), Linux::Alma);
+        assert_eq!(Linux::from_str("centos").unwrap(), Linux::CentOs);
+        assert_eq!(Linux::from_str("rhel").unwrap(), Linux::Rhel);
+        assert_eq!(Linux::from_str("rocky").unwrap(), Linux::Rocky);
         assert_eq!(Linux::from_str("linux").unwrap(), Linux::Unknown);
     }
 }